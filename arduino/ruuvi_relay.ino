@@ -64,6 +64,13 @@ void put_message(unsigned char *buf, int dlen) {
   Serial.println("}}}");
 }
 
+// Writes the 6 bytes of an "aa:bb:cc:dd:ee:ff" address to out.
+void put_address(uint8_t *out, String address) {
+  for (int i = 0; i < 6; i++) {
+    out[i] = strtoul(address.substring(i * 3, i * 3 + 2).c_str(), NULL, 16);
+  }
+}
+
 void loop() {
   if (check_timer(&stop_scan_timer)) {
     Serial.println("stop scan");
@@ -98,8 +105,17 @@ void loop() {
       int len_with_crc32 = dlen + 4;
       uint8_t buf[len_with_crc32];
       if (peripheral.manufacturerData(buf+4, dlen)) {
-        if ((dlen > 2) && (buf[4] == 0x99) && (buf[5] == 0x04) && (buf[6] == 5)) {
-          put_message(buf, dlen);
+        // Pass on every Ruuvi format; the bridge decides what it understands.
+        if ((dlen > 2) && (buf[4] == 0x99) && (buf[5] == 0x04)) {
+          if (buf[6] == 3) {
+            // Format 3 does not carry the MAC, so append it.
+            uint8_t with_address[len_with_crc32 + 6];
+            memcpy(with_address, buf, len_with_crc32);
+            put_address(with_address + len_with_crc32, peripheral.address());
+            put_message(with_address, dlen + 6);
+          } else {
+            put_message(buf, dlen);
+          }
         }
       }
     }
@@ -0,0 +1,197 @@
+//! Webhook notifications when readings cross configured thresholds.
+
+use crate::Sink;
+use hyper::{header::CONTENT_TYPE, Body, Client, Request, Uri};
+use log::warn;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Readings that rules can refer to, with how far back past its threshold
+/// a reading must come by default for an alert to resolve: about what
+/// each reading wanders by between measurements.
+const METRICS: [(&str, f64); 4] = [
+    ("temperature", 0.5),
+    ("humidity", 2.0),
+    ("pressure", 0.1),
+    ("battery", 0.05),
+];
+
+/// Notifications waiting to be posted. Beyond this, new ones are dropped
+/// rather than holding up decoding.
+const QUEUE_LENGTH: usize = 64;
+
+#[derive(Clone)]
+pub struct Rule {
+    mac: Option<[u8; 6]>,
+    metric: &'static str,
+    above: bool,
+    threshold: f64,
+    hysteresis: f64,
+}
+
+/// Parses an http:// webhook URL. There is no TLS client to post to
+/// https:// ones with.
+pub fn parse_webhook(s: &str) -> Result<Uri, String> {
+    let uri: Uri = s.parse().map_err(|e| format!("{}: {}", s, e))?;
+    if uri.scheme_str() != Some("http") || uri.host().is_none() {
+        return Err(format!("expected http://HOST[:PORT]/PATH, got {}", s));
+    }
+    Ok(uri)
+}
+
+/// Parses `[MAC/]METRIC>VALUE[~HYSTERESIS]` or the same with `<`.
+pub fn parse_rule(s: &str) -> Result<Rule, String> {
+    let (mac, rest) = match s.split_once('/') {
+        Some((mac, rest)) => (Some(crate::parse_mac(mac)?), rest),
+        None => (None, s),
+    };
+    let (metric, above, threshold) = if let Some((metric, threshold)) = rest.split_once('>') {
+        (metric, true, threshold)
+    } else if let Some((metric, threshold)) = rest.split_once('<') {
+        (metric, false, threshold)
+    } else {
+        return Err(format!("expected METRIC>VALUE or METRIC<VALUE, got {}", s));
+    };
+    let (metric, default_hysteresis) =
+        METRICS
+            .into_iter()
+            .find(|(m, _)| *m == metric)
+            .ok_or_else(|| {
+                let names = METRICS.map(|(m, _)| m);
+                format!("unknown metric {}, expected one of {:?}", metric, names)
+            })?;
+    let (threshold, hysteresis) = match threshold.split_once('~') {
+        Some((threshold, hysteresis)) => (threshold, Some(hysteresis)),
+        None => (threshold, None),
+    };
+    let number = |s: &str| s.parse::<f64>().map_err(|e| format!("{}: {}", s, e));
+    let hysteresis = match hysteresis {
+        Some(h) => number(h)?,
+        None => default_hysteresis,
+    };
+    Ok(Rule {
+        mac,
+        metric,
+        above,
+        threshold: number(threshold)?,
+        hysteresis,
+    })
+}
+
+impl Rule {
+    /// Once a rule is firing, the value has to come back past the
+    /// threshold by the rule's hysteresis to resolve, so that a value
+    /// hovering at the boundary does not flap.
+    fn breached(&self, value: f64, firing: bool) -> bool {
+        let margin = if firing { self.hysteresis } else { 0.0 };
+        if self.above {
+            value > self.threshold - margin
+        } else {
+            value < self.threshold + margin
+        }
+    }
+}
+
+pub struct Alerter {
+    rules: Vec<Rule>,
+    /// The rules currently firing, by sensor and rule index.
+    firing: Mutex<HashSet<([u8; 6], usize)>>,
+    queue: mpsc::Sender<String>,
+}
+
+impl Alerter {
+    /// Starts a task that posts notifications to `url`, one at a time.
+    pub fn spawn(url: Uri, rules: Vec<Rule>) -> Self {
+        let (queue, mut pending) = mpsc::channel::<String>(QUEUE_LENGTH);
+        tokio::spawn(async move {
+            let client = Client::new();
+            while let Some(body) = pending.recv().await {
+                let req = Request::post(url.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap();
+                match client.request(req).await {
+                    Ok(response) if response.status().is_success() => (),
+                    Ok(response) => warn!("Alert webhook returned {}", response.status()),
+                    Err(e) => warn!("Alert webhook failed: {}", e),
+                }
+            }
+        });
+        Alerter {
+            rules,
+            firing: Mutex::new(HashSet::new()),
+            queue,
+        }
+    }
+}
+
+impl Sink for Alerter {
+    /// Checks a sensor's readings against the rules and queues a
+    /// notification for each rule that starts or stops firing.
+    fn record(&self, mac: &[u8; 6], name: &str, readings: &[(&'static str, Option<f64>)]) {
+        let mut firing = self.firing.lock().unwrap();
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.mac.is_some_and(|m| m != *mac) {
+                continue;
+            }
+            let value = match readings.iter().find(|(metric, _)| *metric == rule.metric) {
+                Some((_, Some(value))) => *value,
+                _ => continue,
+            };
+            let was_firing = firing.contains(&(*mac, i));
+            let breached = rule.breached(value, was_firing);
+            if breached == was_firing {
+                continue;
+            }
+            if breached {
+                firing.insert((*mac, i));
+            } else {
+                firing.remove(&(*mac, i));
+            }
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_secs());
+            let body = json!({
+                "status": if breached { "firing" } else { "resolved" },
+                "mac": crate::mac_string(mac),
+                "name": name,
+                "metric": rule.metric,
+                "value": value,
+                "threshold": rule.threshold,
+                "timestamp": timestamp,
+            })
+            .to_string();
+            if self.queue.try_send(body).is_err() {
+                warn!("Alert queue full, dropping notification for {}", name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hysteresis_per_metric_or_rule() {
+        let rule = parse_rule("battery<2.5").unwrap();
+        assert!(rule.breached(2.49, false));
+        assert!(rule.breached(2.54, true));
+        assert!(!rule.breached(2.56, true));
+        let rule = parse_rule("c8:1f:2a:3b:4c:5d/temperature>8~1").unwrap();
+        assert!(!rule.breached(8.0, false));
+        assert!(rule.breached(7.5, true));
+        assert!(!rule.breached(6.9, true));
+        assert!(parse_rule("humidity>80~x").is_err());
+    }
+
+    #[test]
+    fn webhook_must_be_http() {
+        assert!(parse_webhook("http://localhost:8080/alert").is_ok());
+        assert!(parse_webhook("https://example.com/alert").is_err());
+        assert!(parse_webhook("/alert").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! Battery charge estimates from the voltage a tag reports.
+//!
+//! Lithium coin cells hold close to 3V for most of their life and then fall
+//! off quickly, and how quickly depends on the cell's capacity relative to
+//! the load. The curves here are rough piecewise-linear fits to that shape
+//! at RuuviTag-like loads; treat the result as a guide for when to order
+//! replacements, not a fuel gauge.
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CellType {
+    Cr2032,
+    Cr2450,
+    /// What RuuviTags ship with.
+    Cr2477,
+}
+
+/// (Volts, percent) points in descending order of voltage.
+fn curve(cell: CellType) -> &'static [(f64, f64)] {
+    match cell {
+        CellType::Cr2032 => &[
+            (3.0, 100.0),
+            (2.9, 70.0),
+            (2.8, 45.0),
+            (2.7, 25.0),
+            (2.6, 12.0),
+            (2.5, 5.0),
+            (2.2, 0.0),
+        ],
+        CellType::Cr2450 => &[
+            (3.0, 100.0),
+            (2.9, 75.0),
+            (2.8, 52.0),
+            (2.7, 32.0),
+            (2.6, 16.0),
+            (2.5, 7.0),
+            (2.1, 0.0),
+        ],
+        CellType::Cr2477 => &[
+            (3.0, 100.0),
+            (2.9, 80.0),
+            (2.8, 60.0),
+            (2.7, 40.0),
+            (2.6, 20.0),
+            (2.5, 10.0),
+            (2.0, 0.0),
+        ],
+    }
+}
+
+/// Estimated remaining charge in percent, clamped to 0..=100.
+pub fn percent(cell: CellType, volts: f64) -> f64 {
+    let points = curve(cell);
+    if volts >= points[0].0 {
+        return points[0].1;
+    }
+    for pair in points.windows(2) {
+        let ((v_hi, p_hi), (v_lo, p_lo)) = (pair[0], pair[1]);
+        if volts >= v_lo {
+            return p_lo + (volts - v_lo) / (v_hi - v_lo) * (p_hi - p_lo);
+        }
+    }
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(got: f64, want: f64) {
+        assert!((got - want).abs() < 1e-9, "{} != {}", got, want);
+    }
+
+    #[test]
+    fn full_at_and_above_three_volts() {
+        for cell in [CellType::Cr2032, CellType::Cr2450, CellType::Cr2477] {
+            assert_eq!(percent(cell, 3.0), 100.0);
+            assert_eq!(percent(cell, 3.3), 100.0);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        assert_close(percent(CellType::Cr2032, 2.85), 57.5);
+        assert_close(percent(CellType::Cr2450, 2.85), 63.5);
+        assert_close(percent(CellType::Cr2477, 2.85), 70.0);
+    }
+
+    #[test]
+    fn empty_at_bottom_of_curve() {
+        for (cell, bottom) in [
+            (CellType::Cr2032, 2.2),
+            (CellType::Cr2450, 2.1),
+            (CellType::Cr2477, 2.0),
+        ] {
+            assert_eq!(percent(cell, bottom), 0.0);
+            assert_eq!(percent(cell, bottom - 0.1), 0.0);
+            assert!(percent(cell, bottom + 0.1) > 0.0);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Decoder throughput benchmark, for comparing changes to got_message.
+
+use crate::{format, got_message, Config, SensorState, SystemClock};
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::Mutex;
+
+/// Builds a CRC-prefixed format 5 frame for one of 256 synthetic tags.
+fn synthetic_frame(i: u8, seq: u16) -> Vec<u8> {
+    let mut msg = vec![0x99, 0x04, 5];
+    msg.extend_from_slice(&(4000 + i as i16).to_be_bytes()); // temperature
+    msg.extend_from_slice(&(16000 + i as u16).to_be_bytes()); // humidity
+    msg.extend_from_slice(&(50000 + i as u16).to_be_bytes()); // pressure
+    msg.extend_from_slice(&[0, 4, 0xff, 0xfc, 0x04, 0x0c]); // acceleration
+    msg.extend_from_slice(&(1400u16 << 5 | 12).to_be_bytes()); // power
+    msg.push(i); // movement
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend_from_slice(&[0xc0, 0xbe, 0xef, 0x00, 0x00, i]);
+    format::with_crc(&msg)
+}
+
+/// Feeds `frames` synthetic frames through got_message and prints one
+/// line of space-separated key=value results.
+pub async fn run(frames: usize) {
+    // A new sensor is logged at info level, and this has 256 of them.
+    log::set_max_level(log::LevelFilter::Warn.min(log::max_level()));
+    let config = &Config::default();
+    let sensors = Mutex::new(HashMap::<[u8; 6], SensorState>::new());
+    // Two rounds with different sequence numbers, so that no frame is
+    // dropped as a duplicate of the tag's previous one.
+    let msgs: Vec<_> = (0..512)
+        .map(|n| synthetic_frame(n as u8, n as u16 / 256))
+        .collect();
+    let mut latencies = Vec::with_capacity(frames);
+    let start = Instant::now();
+    for msg in msgs.iter().cycle().take(frames) {
+        let t = Instant::now();
+        got_message(msg, &sensors, config, &SystemClock).await;
+        latencies.push(t.elapsed());
+    }
+    let total = start.elapsed();
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100].as_nanos();
+    println!(
+        "frames={} seconds={:.3} frames_per_second={:.0} p50_ns={} p90_ns={} p99_ns={} max_ns={}",
+        frames,
+        total.as_secs_f64(),
+        frames as f64 / total.as_secs_f64(),
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100),
+    );
+}
@@ -0,0 +1,313 @@
+//! Input straight from a local Bluetooth adapter, without an Arduino. The
+//! adapter is put into LE scanning through a raw HCI socket, as `hcitool
+//! lescan` does, and each advertising report carrying Ruuvi manufacturer
+//! data is framed as the Arduino would relay it and goes through
+//! got_message like any other frame. This needs CAP_NET_RAW and
+//! CAP_NET_ADMIN.
+//!
+//! This is experimental, and --bluetooth needs --experimental-raw-hci. It
+//! has not been tested on hardware. bluetoothd knows nothing of the scan
+//! and drives the same adapter, so it may turn scanning off or change its
+//! parameters under us, and we may upset its own discovery. Stop
+//! bluetoothd or give it a different adapter. A BlueZ D-Bus backend would
+//! share the adapter properly.
+
+use crate::{got_message, Config, OpenDevice, SensorState, SystemClock};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::Mutex;
+
+const BTPROTO_HCI: libc::c_int = 1;
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+const HCI_CHANNEL_RAW: u16 = 0;
+
+const HCI_COMMAND_PKT: u8 = 0x01;
+const HCI_EVENT_PKT: u8 = 0x04;
+const EVT_CMD_COMPLETE: u8 = 0x0e;
+const EVT_CMD_STATUS: u8 = 0x0f;
+const EVT_LE_META_EVENT: u8 = 0x3e;
+const EVT_LE_ADVERTISING_REPORT: u8 = 0x02;
+
+const LE_SET_SCAN_PARAMETERS: u16 = 0x200b;
+const LE_SET_SCAN_ENABLE: u16 = 0x200c;
+
+/// How long to wait for the adapter to answer a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[repr(C)]
+struct SockaddrHci {
+    family: libc::sa_family_t,
+    dev: u16,
+    channel: u16,
+}
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Opens a raw HCI socket on adapter `dev` that receives only LE meta
+/// events and the answers to commands.
+fn open(dev: u16) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket calls on a descriptor owned by `fd`, with
+    // arguments that outlive the calls.
+    unsafe {
+        let fd = check(libc::socket(
+            libc::AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            BTPROTO_HCI,
+        ))?;
+        let fd = OwnedFd::from_raw_fd(fd);
+        let addr = SockaddrHci {
+            family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            dev,
+            channel: HCI_CHANNEL_RAW,
+        };
+        check(libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const SockaddrHci as *const libc::sockaddr,
+            std::mem::size_of::<SockaddrHci>() as libc::socklen_t,
+        ))?;
+        let filter = HciFilter {
+            type_mask: 1 << HCI_EVENT_PKT,
+            event_mask: [
+                1 << EVT_CMD_COMPLETE | 1 << EVT_CMD_STATUS,
+                1 << (EVT_LE_META_EVENT - 32),
+            ],
+            opcode: 0,
+        };
+        check(libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_HCI,
+            HCI_FILTER,
+            &filter as *const HciFilter as *const libc::c_void,
+            std::mem::size_of::<HciFilter>() as libc::socklen_t,
+        ))?;
+        Ok(fd)
+    }
+}
+
+fn send_command(fd: &OwnedFd, opcode: u16, params: &[u8]) -> io::Result<()> {
+    let mut packet = vec![HCI_COMMAND_PKT];
+    packet.extend_from_slice(&opcode.to_le_bytes());
+    packet.push(params.len() as u8);
+    packet.extend_from_slice(params);
+    // SAFETY: the buffer is valid for its length.
+    let written = unsafe {
+        libc::write(
+            fd.as_raw_fd(),
+            packet.as_ptr() as *const libc::c_void,
+            packet.len(),
+        )
+    };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The status in a command complete or command status event answering
+/// `opcode`, if `event` is one.
+fn command_status(event: &[u8], opcode: u16) -> Option<u8> {
+    let [lo, hi] = opcode.to_le_bytes();
+    match *event {
+        [HCI_EVENT_PKT, EVT_CMD_COMPLETE, _, _, l, h, status, ..] if [l, h] == [lo, hi] => {
+            Some(status)
+        }
+        [HCI_EVENT_PKT, EVT_CMD_STATUS, _, status, _, l, h, ..] if [l, h] == [lo, hi] => {
+            Some(status)
+        }
+        _ => None,
+    }
+}
+
+/// Reads the next packet from the socket into `buffer`, returning its
+/// length.
+async fn read_packet(fd: &AsyncFd<OwnedFd>, buffer: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.readable().await?;
+        let count = guard.try_io(|fd| {
+            // SAFETY: the buffer is valid for its length.
+            let count = unsafe {
+                libc::read(
+                    fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if count < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(count as usize)
+            }
+        });
+        match count {
+            Ok(count) => return count,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Sends a command and waits for the adapter to answer it, failing if it
+/// reports an error.
+async fn command(fd: &AsyncFd<OwnedFd>, opcode: u16, params: &[u8]) -> io::Result<()> {
+    send_command(fd.get_ref(), opcode, params)?;
+    let mut buffer = [0u8; 260];
+    let answer = tokio::time::timeout(COMMAND_TIMEOUT, async {
+        loop {
+            let count = read_packet(fd, &mut buffer).await?;
+            if count == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            if let Some(status) = command_status(&buffer[..count], opcode) {
+                return Ok(status);
+            }
+        }
+    });
+    let status = answer.await.map_err(|_| {
+        let message = format!("no answer to HCI command {:#06x}", opcode);
+        io::Error::new(io::ErrorKind::TimedOut, message)
+    })??;
+    if status != 0 {
+        return Err(io::Error::other(format!(
+            "HCI command {:#06x} failed with status {:#04x}",
+            opcode, status
+        )));
+    }
+    Ok(())
+}
+
+/// Turns scanning off when dropped, however the scan ends, so that the
+/// adapter does not go on scanning for nobody.
+struct Scanning<'a>(&'a OwnedFd);
+
+impl Drop for Scanning<'_> {
+    fn drop(&mut self) {
+        send_command(self.0, LE_SET_SCAN_ENABLE, &[0, 0]).ok();
+    }
+}
+
+/// The Ruuvi frames in an LE advertising report event, with the event
+/// header already stripped.
+fn frames(mut report: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let Some((&count, rest)) = report.split_first() else {
+        return frames;
+    };
+    report = rest;
+    for _ in 0..count {
+        // Event type, address type, address, data length, data, RSSI.
+        if report.len() < 9 {
+            break;
+        }
+        let len = report[8] as usize;
+        if report.len() < 10 + len {
+            break;
+        }
+        let mut mac: [u8; 6] = report[2..8].try_into().unwrap();
+        // Addresses go over HCI least significant byte first.
+        mac.reverse();
+        if let Some(data) = crate::gateway::ruuvi_data(&report[9..9 + len]) {
+            frames.extend(crate::format::relay_frame(data, Some(mac)));
+        }
+        report = &report[10 + len..];
+    }
+    frames
+}
+
+/// Scans on adapter `dev` and feeds what it hears through got_message
+/// until the adapter fails or goes away.
+pub async fn scan(
+    dev: u16,
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+) -> io::Result<()> {
+    let fd = AsyncFd::new(open(dev)?)?;
+    // Scanning may have been left on, and the parameters cannot be set
+    // while it is. Turning it off when it is already off fails, harmlessly.
+    command(&fd, LE_SET_SCAN_ENABLE, &[0, 0]).await.ok();
+    // Passive scanning, 10ms interval and window, public own address, no
+    // whitelist.
+    command(&fd, LE_SET_SCAN_PARAMETERS, &[0, 0x10, 0, 0x10, 0, 0, 0]).await?;
+    // Enabled, without duplicate filtering so that every measurement is
+    // reported.
+    command(&fd, LE_SET_SCAN_ENABLE, &[1, 0]).await?;
+    let _scanning = Scanning(fd.get_ref());
+    let _open = OpenDevice::new();
+    let mut buffer = [0u8; 260];
+    loop {
+        let count = read_packet(&fd, &mut buffer).await?;
+        if count == 0 {
+            return Ok(());
+        }
+        let event = &buffer[..count];
+        // Packet type, event code, length, subevent.
+        if event.len() < 4
+            || event[0] != HCI_EVENT_PKT
+            || event[1] != EVT_LE_META_EVENT
+            || event[3] != EVT_LE_ADVERTISING_REPORT
+        {
+            continue;
+        }
+        for msg in frames(&event[4..]) {
+            got_message(&msg, sensors, config, &SystemClock).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_frames_from_report() {
+        let adv = [
+            &[0x02, 0x01, 0x06, 0x11, 0xff, 0x99, 0x04][..],
+            &[0x03, 0x29, 0x1a, 0x1e, 0xce, 0x1e, 0xfc, 0x18],
+            &[0xf9, 0x42, 0x02, 0xca, 0x0b, 0x53],
+        ]
+        .concat();
+        let mut report = vec![1, 0, 1, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11];
+        report.push(adv.len() as u8);
+        report.extend_from_slice(&adv);
+        report.push(0xc4);
+        let frames = frames(&report);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(&frames[0][4..7], &[0x99, 0x04, 3]);
+        assert_eq!(&frames[0][20..], &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    }
+
+    #[test]
+    fn finds_command_status() {
+        // Command complete for LE Set Scan Enable, then command status for
+        // LE Set Scan Parameters, each with status 0x0c, Command
+        // Disallowed.
+        let complete = [HCI_EVENT_PKT, EVT_CMD_COMPLETE, 4, 1, 0x0c, 0x20, 0x0c];
+        assert_eq!(command_status(&complete, LE_SET_SCAN_ENABLE), Some(0x0c));
+        assert_eq!(command_status(&complete, LE_SET_SCAN_PARAMETERS), None);
+        let status = [HCI_EVENT_PKT, EVT_CMD_STATUS, 4, 0x0c, 1, 0x0b, 0x20];
+        assert_eq!(command_status(&status, LE_SET_SCAN_PARAMETERS), Some(0x0c));
+        let report = [
+            HCI_EVENT_PKT,
+            EVT_LE_META_EVENT,
+            2,
+            EVT_LE_ADVERTISING_REPORT,
+            0,
+        ];
+        assert_eq!(command_status(&report, LE_SET_SCAN_ENABLE), None);
+    }
+}
@@ -0,0 +1,332 @@
+//! Field layouts of the Ruuvi data formats got_message decodes, as they
+//! appear in a CRC-prefixed frame from the Arduino: four bytes of CRC32,
+//! the 0x99 0x04 manufacturer ID, then the format byte at offset 6.
+//!
+//! A frame may batch several records. Each follows the last as it would
+//! follow the CRC alone, and the CRC and any HMAC cover them all, so two
+//! format 5 records make a frame of the CRC32, the first record's 26
+//! bytes from 0x99 0x04 on, then the second's. A record's four bytes
+//! before it stand in for its CRC. Decoding stops at a record in a format
+//! whose length isn't known, and ignores trailing bytes that don't start
+//! 0x99 0x04.
+
+use crc32fast::Hasher;
+use std::fmt;
+use std::ops::Range;
+
+/// Length of a format 5 frame, which carries its own MAC.
+const FORMAT5_LEN: usize = 30;
+/// Length of a format 3 frame once the Arduino has appended the MAC, which
+/// format 3 itself does not carry.
+const FORMAT3_LEN: usize = 26;
+/// Length of a format 3 frame as the tag sends it.
+const FORMAT3_BARE_LEN: usize = 20;
+
+/// The measurements in one frame. Fields the tag reported as not
+/// available are None.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub mac: [u8; 6],
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub pressure: Option<f64>,
+    pub battery: Option<f64>,
+    /// X, Y and Z in g.
+    pub acceleration: [Option<f64>; 3],
+    /// Transmit power in dBm.
+    pub tx_power: Option<f64>,
+    pub movement: Option<u8>,
+    pub sequence: Option<u16>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// Too short for the format it claims to be.
+    Short,
+    /// The MAC is all ones, meaning the tag did not fill it in.
+    MissingMac,
+    /// A format 3 frame without the MAC the Arduino should have appended.
+    NotRelayed,
+}
+
+impl ParseError {
+    /// The reason label this is counted under.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ParseError::Short => "short",
+            ParseError::MissingMac | ParseError::NotRelayed => "missing_mac",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Short => write!(f, "too short for its data format"),
+            ParseError::MissingMac => write!(f, "missing MAC"),
+            ParseError::NotRelayed => write!(
+                f,
+                "format 3 frame without MAC; is the Arduino firmware up to date?"
+            ),
+        }
+    }
+}
+
+impl Measurement {
+    /// Total acceleration in g, if all three axes are available.
+    pub fn acceleration_magnitude(&self) -> Option<f64> {
+        let [x, y, z] = self.acceleration;
+        Some((x? * x? + y? * y? + z? * z?).sqrt())
+    }
+}
+
+/// Length of a record in format `format` as it follows the CRC, or None
+/// if the format is not decoded.
+pub fn record_len(format: u8) -> Option<usize> {
+    match format {
+        5 => Some(FORMAT5_LEN - 4),
+        3 => Some(FORMAT3_LEN - 4),
+        _ => None,
+    }
+}
+
+/// Where the MAC is in a frame claiming format `format`, whether or not
+/// the frame is long enough to hold it.
+pub fn mac_range(format: Option<u8>) -> Range<usize> {
+    match format {
+        Some(3) => 20..26,
+        _ => 24..30,
+    }
+}
+
+/// Prefixes `data` with its CRC32, as the Arduino frames it.
+pub fn with_crc(data: &[u8]) -> Vec<u8> {
+    let mut h = Hasher::new();
+    h.update(data);
+    let mut msg = h.finalize().to_be_bytes().to_vec();
+    msg.extend_from_slice(data);
+    msg
+}
+
+/// Frames Ruuvi manufacturer data, from the company ID on, as the Arduino
+/// relays it: format 3, which has no MAC, gets the sender's appended, and
+/// the CRC32 goes in front. None for format 3 when the sender's MAC is not
+/// known.
+pub fn relay_frame(data: &[u8], mac: Option<[u8; 6]>) -> Option<Vec<u8>> {
+    if data.get(2) != Some(&3) {
+        return Some(with_crc(data));
+    }
+    Some(with_crc(&[data, &mac?].concat()))
+}
+
+fn be16(msg: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(msg[at..at + 2].try_into().unwrap())
+}
+
+fn check_mac(measurement: Measurement) -> Result<Measurement, ParseError> {
+    if measurement.mac == [0xff; 6] {
+        Err(ParseError::MissingMac)
+    } else {
+        Ok(measurement)
+    }
+}
+
+/// Decodes a format 5 frame.
+/// https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_05.md
+pub fn parse_format5(msg: &[u8]) -> Result<Measurement, ParseError> {
+    if msg.len() < FORMAT5_LEN {
+        return Err(ParseError::Short);
+    }
+    let temperature = be16(msg, 7) as i16;
+    let humidity = be16(msg, 9);
+    let pressure = be16(msg, 11);
+    let power = be16(msg, 19) >> 5;
+    let tx_power = be16(msg, 19) & 0x1f;
+    let acceleration = [13, 15, 17].map(|at| {
+        let raw = be16(msg, at) as i16;
+        (raw != i16::MIN).then_some(raw as f64 * 0.001)
+    });
+    let sequence = be16(msg, 22);
+    check_mac(Measurement {
+        mac: msg[24..30].try_into().unwrap(),
+        temperature: (temperature != i16::MIN).then_some(temperature as f64 * 0.005),
+        humidity: (humidity != u16::MAX).then_some(humidity as f64 * 0.0025),
+        pressure: (pressure != u16::MAX).then_some(pressure as f64 / 1000.0 + 50.0),
+        battery: (power != 2047).then_some(power as f64 / 1000.0 + 1.6),
+        acceleration,
+        tx_power: (tx_power != 0x1f).then_some(-40.0 + tx_power as f64 * 2.0),
+        movement: (msg[21] != u8::MAX).then_some(msg[21]),
+        sequence: (sequence != u16::MAX).then_some(sequence),
+    })
+}
+
+/// Decodes a format 3 frame with the MAC appended. Format 3 has no
+/// not-available values, so every field it has is present.
+/// https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_03.md
+pub fn parse_format3(msg: &[u8]) -> Result<Measurement, ParseError> {
+    if msg.len() < FORMAT3_LEN {
+        return Err(if msg.len() >= FORMAT3_BARE_LEN {
+            ParseError::NotRelayed
+        } else {
+            ParseError::Short
+        });
+    }
+    // Sign and magnitude, then hundredths.
+    let magnitude = (msg[8] & 0x7f) as f64 + msg[9] as f64 / 100.0;
+    let temperature = if msg[8] & 0x80 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    };
+    check_mac(Measurement {
+        mac: msg[20..26].try_into().unwrap(),
+        temperature: Some(temperature),
+        humidity: Some(msg[7] as f64 * 0.5),
+        pressure: Some(be16(msg, 10) as f64 / 1000.0 + 50.0),
+        battery: Some(be16(msg, 18) as f64 / 1000.0),
+        acceleration: [12, 14, 16].map(|at| Some(be16(msg, at) as i16 as f64 * 0.001)),
+        tx_power: None,
+        movement: None,
+        sequence: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0xcb, 0xb8, 0x33, 0x4c, 0x88, 0x4f];
+
+    /// Wraps a payload from the Ruuvi test vectors, starting at the format
+    /// byte, as the Arduino would relay it. The CRC is not checked here.
+    fn frame(payload: &str) -> Vec<u8> {
+        let mut msg = vec![0, 0, 0, 0, 0x99, 0x04];
+        for i in (0..payload.len()).step_by(2) {
+            msg.push(u8::from_str_radix(&payload[i..i + 2], 16).unwrap());
+        }
+        msg
+    }
+
+    fn assert_near(got: Option<f64>, want: f64) {
+        let got = got.expect("field missing");
+        assert!((got - want).abs() < 1e-9, "got {}, want {}", got, want);
+    }
+
+    #[test]
+    fn format5_valid() {
+        let m = parse_format5(&frame("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F")).unwrap();
+        assert_eq!(m.mac, MAC);
+        assert_near(m.temperature, 24.3);
+        assert_near(m.humidity, 53.49);
+        assert_near(m.pressure, 100.044);
+        assert_near(m.battery, 2.977);
+        assert_near(m.acceleration[0], 0.004);
+        assert_near(m.acceleration[1], -0.004);
+        assert_near(m.acceleration[2], 1.036);
+        assert_near(m.acceleration_magnitude(), 1.036015444);
+        assert_near(m.tx_power, 4.0);
+        assert_eq!(m.movement, Some(66));
+        assert_eq!(m.sequence, Some(205));
+    }
+
+    #[test]
+    fn format5_maximum() {
+        let m = parse_format5(&frame("057FFFFFFEFFFE7FFF7FFF7FFFFFDEFEFFFECBB8334C884F")).unwrap();
+        assert_near(m.temperature, 163.835);
+        assert_near(m.humidity, 163.835);
+        assert_near(m.pressure, 115.534);
+        assert_near(m.battery, 3.646);
+        for axis in m.acceleration {
+            assert_near(axis, 32.767);
+        }
+        assert_near(m.tx_power, 20.0);
+        assert_eq!(m.movement, Some(254));
+        assert_eq!(m.sequence, Some(65534));
+    }
+
+    #[test]
+    fn format5_minimum() {
+        let m = parse_format5(&frame("058001000000008001800180010000000000CBB8334C884F")).unwrap();
+        assert_near(m.temperature, -163.835);
+        assert_near(m.humidity, 0.0);
+        assert_near(m.pressure, 50.0);
+        assert_near(m.battery, 1.6);
+        for axis in m.acceleration {
+            assert_near(axis, -32.767);
+        }
+        assert_near(m.tx_power, -40.0);
+        assert_eq!(m.movement, Some(0));
+        assert_eq!(m.sequence, Some(0));
+    }
+
+    #[test]
+    fn format5_not_available() {
+        // The "invalid values" vector, but with a MAC.
+        let m = parse_format5(&frame("058000FFFFFFFF800080008000FFFFFFFFFFCBB8334C884F")).unwrap();
+        assert_eq!(
+            m,
+            Measurement {
+                mac: MAC,
+                temperature: None,
+                humidity: None,
+                pressure: None,
+                battery: None,
+                acceleration: [None; 3],
+                tx_power: None,
+                movement: None,
+                sequence: None,
+            }
+        );
+        assert_eq!(m.acceleration_magnitude(), None);
+    }
+
+    #[test]
+    fn format5_rejects() {
+        let invalid = frame("058000FFFFFFFF800080008000FFFFFFFFFFFFFFFFFFFFFF");
+        assert_eq!(parse_format5(&invalid), Err(ParseError::MissingMac));
+        assert_eq!(parse_format5(&invalid[..29]), Err(ParseError::Short));
+    }
+
+    #[test]
+    fn format3() {
+        let m = parse_format3(&frame("03291A1ECE1EFC18F94202CA0B53CBB8334C884F")).unwrap();
+        assert_eq!(m.mac, MAC);
+        assert_near(m.humidity, 20.5);
+        assert_near(m.temperature, 26.3);
+        assert_near(m.pressure, 102.766);
+        assert_near(m.battery, 2.899);
+        assert_near(m.acceleration[0], -1.0);
+        assert_near(m.acceleration[1], -1.726);
+        assert_near(m.acceleration[2], 0.714);
+        assert_eq!((m.tx_power, m.movement, m.sequence), (None, None, None));
+
+        let m = parse_format3(&frame("03FF7F63FFFF7FFF7FFF7FFFFFFFCBB8334C884F")).unwrap();
+        assert_near(m.humidity, 127.5);
+        assert_near(m.temperature, 127.99);
+        assert_near(m.pressure, 115.535);
+        assert_near(m.battery, 65.535);
+
+        let m = parse_format3(&frame("0300FF6300008001800180010000CBB8334C884F")).unwrap();
+        assert_near(m.humidity, 0.0);
+        assert_near(m.temperature, -127.99);
+        assert_near(m.pressure, 50.0);
+        assert_near(m.battery, 0.0);
+    }
+
+    #[test]
+    fn format3_rejects() {
+        let bare = frame("03291A1ECE1EFC18F94202CA0B53");
+        assert_eq!(parse_format3(&bare), Err(ParseError::NotRelayed));
+        assert_eq!(parse_format3(&bare[..19]), Err(ParseError::Short));
+    }
+
+    #[test]
+    fn relay_frame_appends_format3_mac() {
+        let format3 = [0x99, 0x04, 3, 0x29, 0x1a];
+        let msg = relay_frame(&format3, Some(MAC)).unwrap();
+        assert_eq!(msg, with_crc(&[&format3[..], &MAC].concat()));
+        assert_eq!(relay_frame(&format3, None), None);
+        let format5 = [0x99, 0x04, 5, 0x12, 0xfc];
+        assert_eq!(relay_frame(&format5, None), Some(with_crc(&format5)));
+    }
+}
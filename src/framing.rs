@@ -0,0 +1,162 @@
+//! Splitting of the byte stream from a serial gateway into frames. Frames
+//! are hex between `{{{` and `}}}`. A longer run of opening braces is taken
+//! as noise followed by an opener: the last three of the run start the
+//! frame. A closing run ends the frame at its third brace, and any further
+//! braces are ignored as interstitial noise.
+
+use crate::FRAME_ABORTS;
+use log::warn;
+
+/// Longest frame, in bytes, the framer accumulates before giving up on it.
+const MAX_FRAME_LEN: usize = 500;
+
+#[derive(Clone, Copy)]
+enum ReadState {
+    Interstitial,
+    Open1,
+    Open2,
+    Nibble1,
+    Nibble2,
+    Close1,
+    Close2,
+}
+
+fn nibble(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        _ => None,
+    }
+}
+
+pub struct Framer {
+    state: ReadState,
+    msg: Vec<u8>,
+    /// The high nibble of the byte being read.
+    high: u8,
+    /// Partial frames abandoned so far.
+    pub aborts: u32,
+}
+
+impl Framer {
+    pub fn new() -> Self {
+        Framer {
+            state: ReadState::Interstitial,
+            msg: Vec::new(),
+            high: 0,
+            aborts: 0,
+        }
+    }
+
+    /// Counts an abandoned partial frame, and goes back to looking for the
+    /// next one.
+    fn abort(&mut self, reason: &str) -> ReadState {
+        FRAME_ABORTS.with_label_values(&[reason]).inc();
+        self.aborts += 1;
+        ReadState::Interstitial
+    }
+
+    /// Takes the next byte, returning the frame it completes if any.
+    pub fn push(&mut self, b: u8) -> Option<Vec<u8>> {
+        let mut frame = None;
+        self.state = match self.state {
+            ReadState::Interstitial if b == b'{' => ReadState::Open1,
+            ReadState::Interstitial => ReadState::Interstitial,
+            ReadState::Open1 if b == b'{' => ReadState::Open2,
+            ReadState::Open2 if b == b'{' => {
+                self.msg = Vec::new();
+                ReadState::Nibble1
+            }
+            ReadState::Open1 | ReadState::Open2 => self.abort("stray_brace"),
+            ReadState::Nibble1 => match nibble(b) {
+                Some(n) => {
+                    self.high = n;
+                    ReadState::Nibble2
+                }
+                None if b == b'}' => ReadState::Close1,
+                // Still in the opening run.
+                None if b == b'{' && self.msg.is_empty() => ReadState::Nibble1,
+                None => self.abort("bad_nibble"),
+            },
+            ReadState::Nibble2 => match nibble(b) {
+                Some(n) => {
+                    self.msg.push(self.high << 4 | n);
+                    if self.msg.len() < MAX_FRAME_LEN {
+                        ReadState::Nibble1
+                    } else {
+                        warn!("Dropped a frame longer than {} bytes", MAX_FRAME_LEN);
+                        self.abort("overlength")
+                    }
+                }
+                None => self.abort("bad_nibble"),
+            },
+            ReadState::Close1 if b == b'}' => ReadState::Close2,
+            ReadState::Close1 => self.abort("stray_brace"),
+            ReadState::Close2 if b == b'}' => {
+                frame = Some(std::mem::take(&mut self.msg));
+                ReadState::Interstitial
+            }
+            ReadState::Close2 => self.abort("stray_brace"),
+        };
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The frames in `input`, and how many partial frames were abandoned.
+    fn split(input: &[u8]) -> (Vec<Vec<u8>>, u32) {
+        let mut framer = Framer::new();
+        let frames = input.iter().filter_map(|b| framer.push(*b)).collect();
+        (frames, framer.aborts)
+    }
+
+    #[test]
+    fn splits_frames() {
+        assert_eq!(split(b"{{{0aFf}}}"), (vec![vec![0x0a, 0xff]], 0));
+        assert_eq!(
+            split(b"noise{{{01}}} {{{02}}}"),
+            (vec![vec![1], vec![2]], 0)
+        );
+    }
+
+    #[test]
+    fn long_opening_run_starts_frame() {
+        assert_eq!(split(b"{{{{01}}}"), (vec![vec![1]], 0));
+        assert_eq!(split(b"{{{{{01}}}"), (vec![vec![1]], 0));
+    }
+
+    #[test]
+    fn long_closing_run_ends_frame() {
+        assert_eq!(split(b"{{{01}}}}"), (vec![vec![1]], 0));
+        assert_eq!(split(b"{{{01}}}}{{{02}}}"), (vec![vec![1], vec![2]], 0));
+    }
+
+    #[test]
+    fn stray_braces_abort() {
+        assert_eq!(split(b"{{01}}}"), (vec![], 1));
+        assert_eq!(split(b"{{{01}}0{{{02}}}"), (vec![vec![2]], 1));
+    }
+
+    #[test]
+    fn bad_nibble_aborts() {
+        assert_eq!(split(b"{{{0g}}}{{{01}}}"), (vec![vec![1]], 1));
+        assert_eq!(split(b"{{{0G}}}"), (vec![], 1));
+        assert_eq!(split(b"{{{0}}}"), (vec![], 1));
+    }
+
+    #[test]
+    fn overlong_frame_aborts() {
+        let mut input = b"{{{".to_vec();
+        input.extend(b"00".repeat(MAX_FRAME_LEN));
+        input.extend(b"}}}{{{01}}}");
+        assert_eq!(split(&input), (vec![vec![1]], 1));
+        let mut input = b"{{{".to_vec();
+        input.extend(b"00".repeat(MAX_FRAME_LEN - 1));
+        input.extend(b"}}}");
+        assert_eq!(split(&input).0[0].len(), MAX_FRAME_LEN - 1);
+    }
+}
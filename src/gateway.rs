@@ -0,0 +1,89 @@
+//! Input from an official Ruuvi Gateway, which POSTs the advertisements it
+//! hears as JSON:
+//!
+//! {"data": {"tags": {"C8:25:2D:8E:9C:2C": {"data": "0201061BFF9904...", ...}}}}
+//!
+//! Each tag's data is the whole advertisement. The Ruuvi manufacturer data
+//! is picked out of it and framed as the Arduino would relay it, so that it
+//! goes through got_message like any other frame.
+
+use serde_json::Value;
+
+/// The manufacturer data in an advertisement, from the 0x99 0x04 company
+/// ID on, if it has Ruuvi's.
+pub fn ruuvi_data(adv: &[u8]) -> Option<&[u8]> {
+    let mut rest = adv;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = len as usize;
+        if len == 0 || tail.len() < len {
+            return None;
+        }
+        let (structure, tail) = tail.split_at(len);
+        // Manufacturer specific data.
+        if structure[0] == 0xff && structure[1..].starts_with(&[0x99, 0x04]) {
+            return Some(&structure[1..]);
+        }
+        rest = tail;
+    }
+    None
+}
+
+/// None unless `s` is all pairs of hex digits.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Turns a Ruuvi Gateway POST body into CRC-prefixed frames. Tags whose
+/// data is not a Ruuvi advertisement are skipped, as are format 3 tags
+/// keyed with something other than a MAC, which their frame needs.
+pub fn frames(body: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let body: Value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let tags = body["data"]["tags"]
+        .as_object()
+        .ok_or("no data.tags object")?;
+    let mut frames = Vec::new();
+    for (mac, tag) in tags {
+        let Some(adv) = tag["data"].as_str().and_then(decode_hex) else {
+            continue;
+        };
+        let Some(data) = ruuvi_data(&adv) else {
+            continue;
+        };
+        frames.extend(crate::format::relay_frame(data, crate::parse_mac(mac).ok()));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crc32fast::Hasher;
+
+    #[test]
+    fn extracts_ruuvi_data() {
+        let body = r#"{"data": {"gw_mac": "AA:BB:CC:DD:EE:FF", "tags": {
+            "CB:B8:33:4C:88:4F": {"rssi": -60,
+                "data": "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F"},
+            "11:22:33:44:55:66": {"rssi": -70,
+                "data": "02010611FF990403291A1ECE1EFC18F94202CA0B53"},
+            "not a MAC": {"data": "02010611FF990403291A1ECE1EFC18F94202CA0B53"},
+            "00:00:00:00:00:01": {"data": "0201060303AAFE"}
+        }}}"#;
+        let mut frames = frames(body.as_bytes()).unwrap();
+        frames.sort_by_key(|f| f[6]);
+        assert_eq!(frames.len(), 2);
+        let format3 = &frames[0];
+        assert_eq!(format3.len(), 26);
+        assert_eq!(&format3[4..7], &[0x99, 0x04, 3]);
+        assert_eq!(&format3[20..], &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        let format5 = &frames[1];
+        assert_eq!(format5.len(), 30);
+        assert_eq!(&format5[4..7], &[0x99, 0x04, 5]);
+        let mut h = Hasher::new();
+        h.update(&format5[4..]);
+        assert_eq!(format5[..4], h.finalize().to_be_bytes());
+    }
+}
@@ -0,0 +1,128 @@
+//! Optional local history of readings in an SQLite table, for setups
+//! without a Prometheus server.
+
+use crate::Sink;
+use log::{error, warn};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Frames waiting to be written. Beyond this, new ones are dropped rather
+/// than holding up decoding.
+const QUEUE_LENGTH: usize = 1024;
+
+/// How often readings older than the retention period are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The readings decoded from one frame.
+struct Row {
+    mac: String,
+    timestamp: i64,
+    readings: Vec<(&'static str, f64)>,
+}
+
+enum Pending {
+    Row(Row),
+    /// Answered once everything queued before it is written.
+    Flush(mpsc::Sender<()>),
+}
+
+pub struct History {
+    queue: SyncSender<Pending>,
+}
+
+impl History {
+    /// Opens or creates the database and starts a thread that writes to
+    /// it, so that SQLite never blocks the reader.
+    pub fn open(path: &Path, retention: Duration) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS readings (
+                 mac TEXT NOT NULL,
+                 metric TEXT NOT NULL,
+                 value REAL NOT NULL,
+                 timestamp INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS readings_by_time
+                 ON readings (timestamp, mac, metric);",
+        )?;
+        let (queue, pending) = mpsc::sync_channel(QUEUE_LENGTH);
+        std::thread::spawn(move || write_rows(conn, pending, retention));
+        Ok(History { queue })
+    }
+
+    /// Waits until every reading recorded so far is written.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.queue.send(Pending::Flush(done)).is_ok() {
+            wait.recv().ok();
+        }
+    }
+}
+
+impl Sink for History {
+    fn record(&self, mac: &[u8; 6], _name: &str, readings: &[(&'static str, Option<f64>)]) {
+        let mac_s = crate::mac_string(mac);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs() as i64);
+        let row = Row {
+            mac: mac_s.clone(),
+            timestamp,
+            readings: readings
+                .iter()
+                .filter_map(|(metric, value)| value.map(|v| (*metric, v)))
+                .collect(),
+        };
+        if self.queue.try_send(Pending::Row(row)).is_err() {
+            warn!("History queue full, dropping reading from {}", mac_s);
+        }
+    }
+}
+
+fn write_rows(mut conn: Connection, pending: Receiver<Pending>, retention: Duration) {
+    let mut next_prune = Instant::now();
+    while let Ok(first) = pending.recv() {
+        // Write whatever has queued up meanwhile in the same transaction.
+        let mut rows = Vec::new();
+        let mut flushes = Vec::new();
+        for p in std::iter::once(first).chain(pending.try_iter()) {
+            match p {
+                Pending::Row(row) => rows.push(row),
+                Pending::Flush(done) => flushes.push(done),
+            }
+        }
+        if let Err(e) = insert(&mut conn, rows.into_iter()) {
+            error!("Writing history failed: {}", e);
+        }
+        if Instant::now() >= next_prune {
+            let cutoff = SystemTime::now()
+                .checked_sub(retention)
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |t| t.as_secs() as i64);
+            if let Err(e) = conn.execute("DELETE FROM readings WHERE timestamp < ?1", [cutoff]) {
+                error!("Pruning history failed: {}", e);
+            }
+            next_prune = Instant::now() + PRUNE_INTERVAL;
+        }
+        for done in flushes {
+            done.send(()).ok();
+        }
+    }
+}
+
+fn insert(conn: &mut Connection, rows: impl Iterator<Item = Row>) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO readings (mac, metric, value, timestamp) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for row in rows {
+            for (metric, value) in row.readings {
+                stmt.execute(params![row.mac, metric, value, row.timestamp])?;
+            }
+        }
+    }
+    tx.commit()
+}
@@ -0,0 +1,26 @@
+//! Quantities derived from temperature and relative humidity.
+
+/// Magnus formula coefficients for water over a plane surface, from
+/// Sonntag (1990), good to about 0.35°C between -45°C and 60°C.
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+
+/// Dew point in degrees Celsius from temperature in degrees Celsius and
+/// relative humidity in percent. Not finite at 0% humidity.
+pub fn dew_point(temperature: f64, humidity: f64) -> f64 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dew_point_matches_reference() {
+        // 16.7°C in published dew point tables.
+        assert!((dew_point(25.0, 60.0) - 16.7).abs() < 0.05);
+        assert!((dew_point(20.0, 100.0) - 20.0).abs() < 1e-9);
+        assert!(!dew_point(20.0, 0.0).is_finite());
+    }
+}
@@ -0,0 +1,190 @@
+//! Writing of every measurement to InfluxDB v2 in line protocol, for
+//! long-term history. Lines are batched and written on an interval rather
+//! than one request per advertisement.
+
+use crate::Sink;
+use hyper::{header::AUTHORIZATION, header::CONTENT_TYPE, Body, Client, Request, Uri};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+
+/// Lines waiting to be batched. Beyond this, new ones are dropped rather
+/// than holding up decoding.
+const QUEUE_LENGTH: usize = 1024;
+
+/// How often batched lines are written.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lines held for the next write at most, so that an unreachable server
+/// does not make the batch grow without bound.
+const MAX_BATCH: usize = 10_000;
+
+enum Pending {
+    Line(String),
+    /// Write what is batched now, then signal.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Escapes a tag value or field key in line protocol.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Percent-encodes a URL query value.
+fn encode_query(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// The line for one measurement, or None if it has no readings.
+fn line(mac: &str, readings: &[(&str, Option<f64>)], timestamp_ns: u128) -> Option<String> {
+    let fields: Vec<_> = readings
+        .iter()
+        .filter_map(|(metric, value)| Some(format!("{}={}", escape(metric), (*value)?)))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "ruuvi,mac={} {} {}",
+        escape(mac),
+        fields.join(","),
+        timestamp_ns
+    ))
+}
+
+pub struct Writer {
+    queue: mpsc::Sender<Pending>,
+    /// Whether lines are being dropped, so that is logged once.
+    dropping: AtomicBool,
+}
+
+impl Writer {
+    /// Starts a task that writes batched lines to BUCKET in ORG on the
+    /// server at `url`.
+    pub fn spawn(url: &Uri, org: &str, bucket: &str, token: &str) -> Result<Self, String> {
+        let write_url: Uri = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ns",
+            url.to_string().trim_end_matches('/'),
+            encode_query(org),
+            encode_query(bucket)
+        )
+        .parse()
+        .map_err(|e| format!("{}: {}", url, e))?;
+        let authorization = format!("Token {}", token);
+        let (queue, mut pending) = mpsc::channel(QUEUE_LENGTH);
+        tokio::spawn(async move {
+            let client = Client::new();
+            let mut batch = Vec::new();
+            let mut failing = false;
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                let done = tokio::select! {
+                    p = pending.recv() => match p {
+                        Some(Pending::Line(line)) => {
+                            if batch.len() < MAX_BATCH {
+                                batch.push(line);
+                            }
+                            continue;
+                        }
+                        Some(Pending::Flush(done)) => Some(done),
+                        None => return,
+                    },
+                    _ = interval.tick() => None,
+                };
+                if !batch.is_empty() {
+                    let req = Request::post(write_url.clone())
+                        .header(AUTHORIZATION, &authorization)
+                        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                        .body(Body::from(batch.join("\n")))
+                        .unwrap();
+                    let error = match client.request(req).await {
+                        Ok(response) if response.status().is_success() => None,
+                        Ok(response) => Some(format!("returned {}", response.status())),
+                        Err(e) => Some(e.to_string()),
+                    };
+                    match error {
+                        None if failing => {
+                            info!("InfluxDB writes are succeeding again");
+                            failing = false;
+                        }
+                        None => (),
+                        Some(e) if !failing => {
+                            warn!("InfluxDB write failed, dropping readings: {}", e);
+                            failing = true;
+                        }
+                        Some(_) => (),
+                    }
+                    batch.clear();
+                }
+                if let Some(done) = done {
+                    done.send(()).ok();
+                }
+            }
+        });
+        Ok(Writer {
+            queue,
+            dropping: AtomicBool::new(false),
+        })
+    }
+
+    /// Waits until every line queued so far has been written, or has
+    /// failed to be.
+    pub async fn flush(&self) {
+        let (done, wait) = oneshot::channel();
+        if self.queue.send(Pending::Flush(done)).await.is_ok() {
+            wait.await.ok();
+        }
+    }
+}
+
+impl Sink for Writer {
+    fn record(&self, mac: &[u8; 6], _name: &str, readings: &[(&'static str, Option<f64>)]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_nanos());
+        let Some(line) = line(&crate::mac_string(mac), readings, timestamp) else {
+            return;
+        };
+        match self.queue.try_send(Pending::Line(line)) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(_) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("InfluxDB queue full, dropping readings until it drains");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_line() {
+        let readings = [
+            ("temperature", Some(24.3)),
+            ("humidity", None),
+            ("pressure", Some(100.044)),
+        ];
+        assert_eq!(
+            line("cb:b8:33:4c:88:4f", &readings, 1700000000000000000).as_deref(),
+            Some(
+                "ruuvi,mac=cb:b8:33:4c:88:4f temperature=24.3,pressure=100.044 1700000000000000000"
+            )
+        );
+        assert_eq!(line("cb:b8:33:4c:88:4f", &[("battery", None)], 0), None);
+        assert_eq!(encode_query("my org/1"), "my%20org%2F1");
+    }
+}
@@ -1,32 +1,1068 @@
+use battery::CellType;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use crc32fast::Hasher;
+use hmac::{Hmac, Mac};
 use hyper::{
+    body::HttpBody,
     header::CONTENT_TYPE,
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Method, Request, Response, Server,
 };
 use lazy_static::lazy_static;
-use prometheus::{opts, register_gauge_vec};
-use prometheus::{Encoder, GaugeVec, TextEncoder};
-use std::collections::HashMap;
-use std::env;
+use log::{debug, error, info, warn};
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{
+    exponential_buckets, Collector, Counter, CounterVec, Encoder, Gauge, GaugeVec, Histogram,
+    HistogramOpts, TextEncoder,
+};
+use prometheus::{
+    opts, register_counter, register_counter_vec, register_gauge, register_gauge_vec,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
 
+/// The label key identifying the sensor, set from --sensor-label before
+/// any metric is registered.
+static SENSOR_LABEL: OnceLock<String> = OnceLock::new();
+
+fn sensor_label() -> &'static str {
+    SENSOR_LABEL.get().map_or("unit", String::as_str)
+}
+
+/// Other label keys on per-sensor series, which --sensor-label must not
+/// clash with.
+const OTHER_SENSOR_LABELS: [&str; 5] = ["axis", "data_format", "category", "metric", "scale"];
+
 lazy_static! {
-    static ref ROOM_TEMPERATURE: GaugeVec =
-        register_gauge_vec!("room_temperature", "Room temperature in degrees", &["unit"]).unwrap();
+    static ref ROOM_TEMPERATURE: GaugeVec = register_gauge_vec!(
+        "room_temperature",
+        "Room temperature in degrees on the scale named by the scale label",
+        &[sensor_label(), "scale"]
+    )
+    .unwrap();
     static ref HUMIDITY: GaugeVec =
-        register_gauge_vec!("humidity", "Humidity in percent", &["unit"]).unwrap();
+        register_gauge_vec!("humidity", "Humidity in percent", &[sensor_label()]).unwrap();
     static ref PRESSURE: GaugeVec =
-        register_gauge_vec!("air_pressure", "Pressure in kPa", &["unit"]).unwrap();
+        register_gauge_vec!("air_pressure", "Pressure in kPa", &[sensor_label()]).unwrap();
+    static ref PRESSURE_FILTERED: GaugeVec = register_gauge_vec!(
+        "air_pressure_filtered",
+        "Low-pass filtered pressure in kPa",
+        &[sensor_label()]
+    )
+    .unwrap();
     static ref BATTERY: GaugeVec =
-        register_gauge_vec!("sensor_battery", "Battery Volts", &["unit"]).unwrap();
+        register_gauge_vec!("sensor_battery", "Battery Volts", &[sensor_label()]).unwrap();
+    static ref BATTERY_PERCENT: GaugeVec = register_gauge_vec!(
+        "sensor_battery_percent",
+        "Estimated remaining battery charge in percent",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref GATEWAY_PROTOCOL_OK: GaugeVec = register_gauge_vec!(
+        "ruuvi_gateway_protocol_ok",
+        "Whether the startup probe saw a valid frame from the gateway",
+        &["device"]
+    )
+    .unwrap();
+    static ref ASSUMED_FORMAT5: Counter = register_counter!(
+        "ruuvi_assumed_format5_total",
+        "Frames of unknown format decoded with the format 5 layout"
+    )
+    .unwrap();
+    static ref DATA_SOURCE: GaugeVec = register_gauge_vec!(
+        "ruuvi_data_source",
+        "Where readings come from; 1 for the active input",
+        &["mode"]
+    )
+    .unwrap();
+    static ref HMAC_FAILURES: Counter = register_counter!(
+        "ruuvi_hmac_failures_total",
+        "Frames rejected because their HMAC did not verify"
+    )
+    .unwrap();
+    static ref RATE_ANOMALY: GaugeVec = register_gauge_vec!(
+        "ruuvi_sensor_rate_anomaly",
+        "1 if the measurement sequence advances at an unexpected rate",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref SWEEPER_LAST_RUN: Gauge = register_gauge!(
+        "ruuvi_sweeper_last_run_seconds",
+        "Unix time at which expired sensors were last swept"
+    )
+    .unwrap();
+    static ref SERIES_BUDGET_EXCEEDED: Gauge = register_gauge!(
+        "ruuvi_series_budget_exceeded",
+        "1 if series are being withheld to stay within --max-series"
+    )
+    .unwrap();
+    static ref DEVICE_PARSE_SUCCESS_RATIO: GaugeVec = register_gauge_vec!(
+        "ruuvi_device_parse_success_ratio",
+        "Fraction of frames from a device, counting those abandoned partway, that arrived intact from an admitted sensor over the last minute",
+        &["device"]
+    )
+    .unwrap();
+    static ref ACCELERATION: GaugeVec = register_gauge_vec!(
+        "acceleration",
+        "Acceleration in g",
+        &[sensor_label(), "axis"]
+    )
+    .unwrap();
+    static ref DEW_POINT: GaugeVec = register_gauge_vec!(
+        "dew_point",
+        "Dew point in degrees on the scale named by the scale label",
+        &[sensor_label(), "scale"]
+    )
+    .unwrap();
+    static ref LAST_SEEN: GaugeVec = register_gauge_vec!(
+        "sensor_last_seen_timestamp_seconds",
+        "Unix time of the sensor's latest accepted frame",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref BUILD_INFO: GaugeVec = register_gauge_vec!(
+        "ruuvi_bridge_build_info",
+        "1, labelled with the version of ruuvi_bridge running",
+        &["version", "commit"]
+    )
+    .unwrap();
+    static ref SENSOR_INFO: GaugeVec = register_gauge_vec!(
+        "ruuvi_sensor_info",
+        "1, labelled with the data format the sensor last sent",
+        &[sensor_label(), "data_format"]
+    )
+    .unwrap();
+    static ref TX_POWER: GaugeVec = register_gauge_vec!(
+        "sensor_tx_power_dbm",
+        "The tag's radio transmit power in dBm",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref MOVEMENT: GaugeVec = register_gauge_vec!(
+        "sensor_movement_count",
+        "Movements the tag has counted, wrapping at 255",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref ACCELERATION_MAGNITUDE: GaugeVec = register_gauge_vec!(
+        "sensor_acceleration_magnitude",
+        "Total acceleration in g",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref STALE: GaugeVec = register_gauge_vec!(
+        "sensor_stale",
+        "1 while the sensor's series are held past its TTL by --stale-grace",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref SEQUENCE: GaugeVec = register_gauge_vec!(
+        "sensor_measurement_sequence",
+        "The tag's measurement sequence number, wrapping at 65535",
+        &[sensor_label()]
+    )
+    .unwrap();
+    static ref COMFORT: GaugeVec = register_gauge_vec!(
+        "ruuvi_comfort",
+        "1 for the comfort category the latest reading falls in",
+        &[sensor_label(), "category"]
+    )
+    .unwrap();
+    static ref OUT_OF_RANGE: CounterVec = register_counter_vec!(
+        "ruuvi_out_of_range_total",
+        "Readings dropped for falling outside the configured bounds",
+        &["metric", sensor_label()]
+    )
+    .unwrap();
+    static ref ACCEPTED_FRAMES: Counter = register_counter!(
+        "ruuvi_frames_accepted_total",
+        "Frames decoded from a sensor"
+    )
+    .unwrap();
+    static ref REJECTED_FRAMES: CounterVec = register_counter_vec!(
+        "ruuvi_frames_rejected_total",
+        "Frames, or readings within them, that did not become metrics",
+        &["reason"]
+    )
+    .unwrap();
+    static ref FRAME_ABORTS: CounterVec = register_counter_vec!(
+        "ruuvi_frame_aborts_total",
+        "Partial frames abandoned by the serial framing parser",
+        &["reason"]
+    )
+    .unwrap();
+    static ref EXPECTED_SENSORS_MISSING: Gauge = register_gauge!(
+        "ruuvi_expected_sensors_missing",
+        "Sensors given with --expect-sensor that have not reported since startup"
+    )
+    .unwrap();
+    static ref UNEXPECTED_SENSORS: Gauge = register_gauge!(
+        "ruuvi_unexpected_sensors",
+        "Reporting sensors that were not given with --expect-sensor"
+    )
+    .unwrap();
+}
+
+const BOUNDED_METRICS: [&str; 4] = ["temperature", "humidity", "pressure", "battery"];
+
+const AXES: [&str; 3] = ["x", "y", "z"];
+
+const COMFORT_CATEGORIES: [&str; 5] =
+    ["comfortable", "too_cold", "too_hot", "too_dry", "too_humid"];
+
+/// Length of the HMAC-SHA256 tag appended to each frame by gateways
+/// configured with a shared key.
+const HMAC_LEN: usize = 32;
+
+/// How long a sensor's series are kept after its last report, unless
+/// overridden with --sensor-ttl.
+const SENSOR_TTL: Duration = Duration::from_secs(300);
+
+/// The sweeper runs this many times per default TTL, so that series go
+/// stale by at most a few percent more than the TTL, but no more often
+/// than once a second nor less often than every 10 seconds.
+const SWEEPS_PER_TTL: u32 = 30;
+
+/// Series every exported sensor gets: temperature, humidity, pressure and
+/// battery. A sensor is only exported if these all fit in the budget.
+/// Temperature takes one more for each --temperature-scale past the first.
+const CORE_SERIES: usize = 4;
+
+/// Series a sensor may additionally get, which are shed first when the
+/// series budget runs short: battery percentage, filtered pressure, the
+/// rate anomaly flag, comfort category, dew point, the three acceleration
+/// axes and their magnitude, TX power, the movement counter, the
+/// measurement sequence number and the last-seen time, the info and stale
+/// series, and a count of out-of-range readings for each bounded metric.
+/// Dew point takes one more for each --temperature-scale past the first.
+const OPTIONAL_SERIES: usize = 15 + BOUNDED_METRICS.len();
+
+/// How often to look for gateways, and to restart readers that stopped.
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The longest the scan interval backs off to while no gateway is found or
+/// readers keep failing.
+const MAX_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// With --log-rejects, the least time between logged rejections for the
+/// same reason.
+const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest Ruuvi Gateway POST body accepted. A gateway in range of a few
+/// hundred tags sends well under this.
+const MAX_GATEWAY_BODY: usize = 1 << 20;
+
+/// How long to wait at shutdown for the last batch to reach InfluxDB.
+const INFLUX_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before reading again after an empty read, with
+/// --zero-read-retries.
+const ZERO_READ_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Window over which each device's parse success ratio is computed.
+const PARSE_RATIO_WINDOW: Duration = Duration::from_secs(60);
+
+/// Minimum wall time over which a sensor's measurement rate is assessed.
+/// Shorter windows are dominated by BLE reception jitter.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+mod alert;
+mod battery;
+mod bench;
+mod bluetooth;
+mod format;
+mod framing;
+mod gateway;
+mod history;
+mod humidity;
+mod influx;
+mod mqtt;
+
+/// Shown by --version, with the Ruuvi data formats got_message decodes.
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("GIT_COMMIT"),
+    ")\nsupported data formats: 3, 5"
+);
+
+const EXAMPLES: &str = "\
+Examples:
+  Find an Arduino gateway and serve metrics on port 9100:
+    ruuvi_bridge [::]:9100
+
+  Also log every rejected frame:
+    RUST_LOG=debug ruuvi_bridge [::]:9100
+
+  Check that the gateway sends valid frames within 30 seconds of opening:
+    ruuvi_bridge --probe-timeout 30 [::]:9100
+
+  Keep series for 10 minutes, and a rarely-reporting tag's for 15:
+    ruuvi_bridge --sensor-ttl 600 --sensor-ttl c8:1f:2a:3b:4c:5d=900 [::]:9100
+
+  Debug a single tag:
+    ruuvi_bridge --trace-mac c8:1f:2a:3b:4c:5d [::]:9100
+
+  Read a particular serial port rather than looking for Arduinos:
+    ruuvi_bridge --device /dev/ttyACM0 [::]:9100
+
+  Read a gateway shared over the network by ser2net:
+    ruuvi_bridge --tcp gateway.local:2000 [::]:9100
+
+  Scan with the local adapter hci1 instead (experimental):
+    ruuvi_bridge --bluetooth=1 --experimental-raw-hci [::]:9100
+
+  Also publish readings to an MQTT broker:
+    ruuvi_bridge --mqtt-url mqtt://localhost [::]:9100";
+
+/// Serves readings from RuuviTags, received through an Arduino BLE
+/// gateway on a serial port, as Prometheus metrics.
+#[derive(Parser)]
+#[command(version, long_version = LONG_VERSION, after_help = EXAMPLES)]
+struct Args {
+    /// Addresses on which to serve metrics, e.g. [::]:9100, separated by
+    /// spaces or commas. Required unless systemd passes in listening
+    /// sockets, which are used instead.
+    #[arg(value_delimiter = ',')]
+    export_listen: Vec<SocketAddr>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read from this serial device instead of looking for Arduinos. May
+    /// be repeated.
+    #[arg(long, value_name = "PATH")]
+    device: Vec<PathBuf>,
+
+    /// Read from a serial port exposed as a raw TCP socket, such as by
+    /// ser2net, instead of looking for Arduinos. May be repeated.
+    #[arg(long, value_name = "HOST:PORT")]
+    tcp: Vec<String>,
+
+    /// Look for gateways with this USB vendor and product ID, in hex. May
+    /// be repeated.
+    #[arg(long, value_name = "VENDOR:PRODUCT", value_parser = parse_usb_id)]
+    #[arg(default_value = "2341:8054")]
+    usb_id: Vec<(u16, u16)>,
+
+    /// Keep series for this long after their sensor's last report instead
+    /// of 300 seconds. With a MAC, applies to that sensor only. May be
+    /// repeated.
+    #[arg(long, value_name = "[MAC=]SECS", value_parser = parse_sensor_ttl)]
+    sensor_ttl: Vec<(Option<[u8; 6]>, Duration)>,
+
+    /// Once a sensor's TTL has passed, hold its series for this many more
+    /// seconds, flagged by sensor_stale, before removing them. Without
+    /// this, they are removed as soon as the TTL passes.
+    #[arg(long, value_name = "SECS")]
+    stale_grace: Option<u64>,
+
+    /// Label key identifying the sensor on its series, valued with its MAC
+    /// or --names name. Changing it renames the label on every series, so
+    /// queries and dashboards have to follow and the old and new series do
+    /// not join up. Alternatively, keep the default and rename the label
+    /// at scrape time with a labelmap or replace rule in Prometheus'
+    /// metric_relabel_configs.
+    #[arg(long, value_name = "NAME", default_value = "unit", value_parser = parse_label_name)]
+    sensor_label: String,
+
+    /// After opening the gateway, expect a valid frame within this many
+    /// seconds and report whether one arrived.
+    #[arg(long, value_name = "SECS")]
+    probe_timeout: Option<u64>,
+
+    /// Decode frames with an unknown format byte as format 5 if they are
+    /// the same length. For clones that mislabel their broadcasts.
+    #[arg(long)]
+    assume_format5: bool,
+
+    /// Require each frame to end with an HMAC-SHA256 of the manufacturer
+    /// data keyed with the contents of this file, less any trailing
+    /// whitespace such as a final newline. The gateway firmware must be
+    /// built to append it, which the sketch in arduino/ does not yet do.
+    #[arg(long, value_name = "PATH")]
+    hmac_key_file: Option<PathBuf>,
+
+    /// Expected interval between measurements on the tags. When given,
+    /// ruuvi_sensor_rate_anomaly flags tags whose sequence number
+    /// advances faster or slower than this.
+    #[arg(long, value_name = "MILLIS", value_parser = clap::value_parser!(u64).range(1..))]
+    measurement_interval_ms: Option<u64>,
+
+    /// How far, as a fraction, the observed measurement rate may stray
+    /// from the expected one before it is flagged.
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.5)]
+    rate_tolerance: f64,
+
+    /// Log every frame from this tag in detail, with the reason it was
+    /// accepted or rejected. The lines are logged at info level, so they
+    /// show without also setting RUST_LOG=debug and drowning them out.
+    #[arg(long, value_name = "MAC", value_parser = parse_mac)]
+    trace_mac: Option<[u8; 6]>,
+
+    /// Upper bound on the number of per-sensor series. Once it is
+    /// approached, optional series are dropped, then new sensors are no
+    /// longer exported until others expire. Their readings still go to
+    /// alerts, history, MQTT and InfluxDB.
+    #[arg(long, value_name = "N")]
+    max_series: Option<usize>,
+
+    /// How many consecutive empty reads from a device that is still
+    /// present to tolerate, retrying a second after each, before reopening
+    /// it.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    zero_read_retries: u32,
+
+    /// Reopen a device that has gone this many seconds without a valid
+    /// frame from an admitted sensor, in case it is stuck sending noise
+    /// or nothing. 0 disables this.
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    frame_watchdog: u64,
+
+    /// Export ruuvi_comfort, classifying each reading by the comfortable
+    /// ranges below.
+    #[arg(long)]
+    comfort: bool,
+
+    /// Comfortable temperature range in degrees Celsius.
+    #[arg(long, value_name = "MIN:MAX", default_value = "18:24", value_parser = parse_range, allow_hyphen_values = true)]
+    comfort_temperature: (f64, f64),
+
+    /// Comfortable relative humidity range in percent.
+    #[arg(long, value_name = "MIN:MAX", default_value = "30:60", value_parser = parse_range)]
+    comfort_humidity: (f64, f64),
+
+    /// Export room_temperature and dew_point in this scale, as given by
+    /// their scale label. May be repeated to export both scales, at the
+    /// cost of two more series for each sensor. /sensors.json uses the
+    /// first.
+    #[arg(long, value_name = "SCALE", default_value = "celsius")]
+    temperature_scale: Vec<TemperatureScale>,
+
+    /// Stamp each sensor's gauges with the time of its latest frame rather
+    /// than leaving Prometheus to use the scrape time. Prometheus does not
+    /// mark series with explicit timestamps stale when they disappear, so
+    /// an expired sensor's last values linger in queries for up to five
+    /// minutes, and a lookback shorter than the sensors' reporting
+    /// interval shows gaps.
+    #[arg(long)]
+    metric_timestamps: bool,
+
+    /// Temperature readings outside this range in degrees Celsius are
+    /// dropped as sensor faults.
+    #[arg(long, value_name = "MIN:MAX", default_value = "-80:100", value_parser = parse_range, allow_hyphen_values = true)]
+    temperature_bounds: (f64, f64),
+
+    /// Relative humidity readings outside this range in percent are
+    /// dropped as sensor faults.
+    #[arg(long, value_name = "MIN:MAX", default_value = "0:100", value_parser = parse_range)]
+    humidity_bounds: (f64, f64),
+
+    /// Pressure readings outside this range in kPa are dropped as sensor
+    /// faults.
+    #[arg(long, value_name = "MIN:MAX", default_value = "30:120", value_parser = parse_range)]
+    pressure_bounds: (f64, f64),
+
+    /// Battery readings outside this range in Volts are dropped as sensor
+    /// faults.
+    #[arg(long, value_name = "MIN:MAX", default_value = "1.6:3.7", value_parser = parse_range)]
+    battery_bounds: (f64, f64),
+
+    /// Export readings however implausible, ignoring the bounds above.
+    #[arg(long)]
+    no_bounds_check: bool,
+
+    /// Coin cell type, for estimating sensor_battery_percent: cr2032,
+    /// cr2450 or cr2477 (the default). Either a default for all tags or
+    /// MAC=TYPE for one tag. May be repeated.
+    #[arg(long, value_name = "[MAC=]TYPE", value_parser = parse_cell_type)]
+    cell_type: Vec<(Option<[u8; 6]>, CellType)>,
+
+    /// A tag that should be reporting. When any are given, the fleet is
+    /// checked against them. May be repeated.
+    #[arg(long, value_name = "MAC", value_parser = parse_mac)]
+    expect_sensor: Vec<[u8; 6]>,
+
+    /// Export only this tag, and others given the same way, dropping
+    /// frames from any other. May be repeated.
+    #[arg(long, value_name = "MAC", value_parser = parse_mac)]
+    allow_sensor: Vec<[u8; 6]>,
+
+    /// Drop frames from this tag, such as a neighbour's in range. May be
+    /// repeated.
+    #[arg(long, value_name = "MAC", value_parser = parse_mac)]
+    deny_sensor: Vec<[u8; 6]>,
+
+    /// Export air_pressure_filtered, low-pass filtered with this time
+    /// constant, for smoother weather trends.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pressure_filter_secs: Option<u64>,
+
+    /// POST a JSON notification to this http:// URL when a reading
+    /// starts or stops breaching an --alert rule.
+    #[arg(long, value_name = "URL", value_parser = alert::parse_webhook, requires = "alert")]
+    alert_webhook: Option<hyper::Uri>,
+
+    /// Alert when a reading crosses a threshold, e.g. temperature>8 or
+    /// c8:1f:2a:3b:4c:5d/battery<2.5. Metrics are temperature (Celsius),
+    /// humidity (percent), pressure (kPa) and battery (Volts). The alert
+    /// resolves once the reading comes back past the threshold by 0.5,
+    /// 2, 0.1 and 0.05 of those respectively, or by AMOUNT if given as
+    /// e.g. battery<2.5~0.1. May be repeated.
+    #[arg(long, value_name = "[MAC/]METRIC>VALUE[~AMOUNT]", value_parser = alert::parse_rule, requires = "alert_webhook")]
+    alert: Vec<alert::Rule>,
+
+    /// Also record every reading in this SQLite database, in a table
+    /// called readings.
+    #[arg(long, value_name = "PATH")]
+    history_db: Option<PathBuf>,
+
+    /// Delete readings older than this many days from --history-db.
+    #[arg(long, value_name = "DAYS", default_value_t = 30)]
+    history_retention_days: u64,
+
+    /// Log rejected frames with their MAC and the reason as warnings, at
+    /// most once every 10 seconds per reason. Otherwise they are only
+    /// logged at debug level.
+    #[arg(long)]
+    log_rejects: bool,
+
+    /// TOML file of "MAC" = "name" lines. Named sensors are exported with
+    /// their name as the sensor label instead of their MAC. No two sensors
+    /// may have the same name.
+    #[arg(long, value_name = "PATH")]
+    names: Option<PathBuf>,
+
+    /// The gateway sends frames without a CRC32 prefix, so take them as
+    /// they are.
+    #[arg(long)]
+    no_crc: bool,
+
+    /// Also accept readings POSTed to /ruuvi by a Ruuvi Gateway. These are
+    /// not authenticated, so do not combine this with --hmac-key-file.
+    #[arg(long, conflicts_with = "hmac_key_file")]
+    gateway_input: bool,
+
+    /// Also scan for tags on this local Bluetooth adapter, hci0 unless
+    /// given, instead of looking for Arduinos. Arduinos named by --device
+    /// or --tcp are still read. Needs CAP_NET_RAW and CAP_NET_ADMIN. Like
+    /// --gateway-input, this cannot be combined with --hmac-key-file.
+    /// Experimental, so it also needs --experimental-raw-hci.
+    #[arg(long, value_name = "N", num_args = 0..=1, require_equals = true)]
+    #[arg(default_missing_value = "0")]
+    #[arg(conflicts_with = "hmac_key_file", requires = "experimental_raw_hci")]
+    bluetooth: Option<u16>,
+
+    /// Accept that --bluetooth drives the adapter through a raw HCI socket
+    /// behind bluetoothd's back. The two compete for the adapter, so stop
+    /// bluetoothd or leave the bridge an adapter of its own. This has not
+    /// been tested on hardware.
+    #[arg(long, requires = "bluetooth")]
+    experimental_raw_hci: bool,
+
+    /// Also publish every reading as JSON to this MQTT broker, e.g.
+    /// mqtt://localhost.
+    #[arg(long, value_name = "URL", value_parser = mqtt::parse_url)]
+    mqtt_url: Option<(String, u16)>,
+
+    /// Publish each sensor's readings to the topic PREFIX/MAC.
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "ruuvi",
+        requires = "mqtt_url"
+    )]
+    mqtt_topic_prefix: String,
+
+    /// Client ID to connect to the MQTT broker with, instead of
+    /// ruuvi_bridge-HOSTNAME. Each bridge on the same broker needs its
+    /// own.
+    #[arg(long, value_name = "ID", requires = "mqtt_url")]
+    mqtt_client_id: Option<String>,
+
+    /// Also write every reading to this InfluxDB v2 server, e.g.
+    /// http://localhost:8086, as the measurement ruuvi tagged with the
+    /// MAC. Writes are batched every 10 seconds.
+    #[arg(long, value_name = "URL")]
+    #[arg(requires_all = ["influxdb_org", "influxdb_bucket", "influxdb_token_file"])]
+    influxdb_url: Option<hyper::Uri>,
+
+    /// InfluxDB organization to write to.
+    #[arg(long, value_name = "ORG", requires = "influxdb_url")]
+    influxdb_org: Option<String>,
+
+    /// InfluxDB bucket to write to.
+    #[arg(long, value_name = "BUCKET", requires = "influxdb_url")]
+    influxdb_bucket: Option<String>,
+
+    /// File holding the InfluxDB API token.
+    #[arg(long, value_name = "PATH", requires = "influxdb_url")]
+    influxdb_token_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decode synthetic frames as fast as possible and report throughput
+    #[command(hide = true)]
+    BenchDecode {
+        #[arg(default_value_t = 1_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+        frames: u64,
+    },
+}
+
+struct Config {
+    ttl: Duration,
+    stale_grace: Option<Duration>,
+    ttl_overrides: HashMap<[u8; 6], Duration>,
+    probe_timeout: Option<Duration>,
+    assume_format5: bool,
+    hmac_key: Option<Vec<u8>>,
+    measurement_interval: Option<Duration>,
+    rate_tolerance: f64,
+    trace_mac: Option<[u8; 6]>,
+    max_series: Option<usize>,
+    zero_read_retries: u32,
+    frame_watchdog: Option<Duration>,
+    comfort: Option<Comfort>,
+    bounds: Bounds,
+    cell_type: CellType,
+    cell_type_overrides: HashMap<[u8; 6], CellType>,
+    expected_sensors: HashSet<[u8; 6]>,
+    /// Those of expected_sensors that have reported since startup.
+    expected_seen: std::sync::Mutex<HashSet<[u8; 6]>>,
+    /// Sensors logged as new since startup, so one that expires and comes
+    /// back isn't logged again.
+    new_logged: std::sync::Mutex<HashSet<[u8; 6]>>,
+    allowed_sensors: HashSet<[u8; 6]>,
+    denied_sensors: HashSet<[u8; 6]>,
+    pressure_filter: Option<Duration>,
+    alerter: Option<alert::Alerter>,
+    history: Option<history::History>,
+    /// When each rejection reason was last logged, with --log-rejects.
+    reject_log: Option<std::sync::Mutex<HashMap<&'static str, Instant>>>,
+    names: HashMap<[u8; 6], String>,
+    gateway_input: bool,
+    no_crc: bool,
+    /// Never empty.
+    temperature_scales: Vec<TemperatureScale>,
+    metric_timestamps: bool,
+    mqtt: Option<mqtt::Publisher>,
+    influx: Option<influx::Writer>,
+}
+
+/// No sinks and nothing optional turned on, for the decode benchmark and
+/// tests.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            ttl: SENSOR_TTL,
+            stale_grace: None,
+            ttl_overrides: HashMap::new(),
+            probe_timeout: None,
+            assume_format5: false,
+            hmac_key: None,
+            measurement_interval: None,
+            rate_tolerance: 0.5,
+            trace_mac: None,
+            max_series: None,
+            zero_read_retries: 0,
+            frame_watchdog: None,
+            comfort: None,
+            bounds: Bounds::UNBOUNDED,
+            cell_type: CellType::Cr2477,
+            cell_type_overrides: HashMap::new(),
+            expected_sensors: HashSet::new(),
+            expected_seen: std::sync::Mutex::new(HashSet::new()),
+            new_logged: std::sync::Mutex::new(HashSet::new()),
+            allowed_sensors: HashSet::new(),
+            denied_sensors: HashSet::new(),
+            pressure_filter: None,
+            alerter: None,
+            history: None,
+            reject_log: None,
+            names: HashMap::new(),
+            gateway_input: false,
+            no_crc: false,
+            temperature_scales: vec![TemperatureScale::Celsius],
+            metric_timestamps: false,
+            mqtt: None,
+            influx: None,
+        }
+    }
+}
+
+impl Config {
+    /// CORE_SERIES, counting temperature once for each scale.
+    fn core_series(&self) -> usize {
+        CORE_SERIES + self.temperature_scales.len() - 1
+    }
+
+    /// OPTIONAL_SERIES, counting dew point once for each scale.
+    fn optional_series(&self) -> usize {
+        OPTIONAL_SERIES + self.temperature_scales.len() - 1
+    }
+
+    fn ttl(&self, mac: &[u8; 6]) -> Duration {
+        self.ttl_overrides.get(mac).copied().unwrap_or(self.ttl)
+    }
+
+    /// The unit label for a sensor: its --names name if it has one, or
+    /// else its MAC.
+    fn unit(&self, mac: &[u8; 6]) -> String {
+        match self.names.get(mac) {
+            Some(name) => name.clone(),
+            None => mac_string(mac),
+        }
+    }
+
+    /// Whether --allow-sensor and --deny-sensor let a sensor through.
+    fn admits(&self, mac: &[u8; 6]) -> bool {
+        (self.allowed_sensors.is_empty() || self.allowed_sensors.contains(mac))
+            && !self.denied_sensors.contains(mac)
+    }
+
+    /// The sinks configured, in the order they are fed.
+    fn sinks(&self) -> impl Iterator<Item = &dyn Sink> {
+        let alerter = self.alerter.as_ref().map(|s| s as &dyn Sink);
+        let history = self.history.as_ref().map(|s| s as &dyn Sink);
+        let mqtt = self.mqtt.as_ref().map(|s| s as &dyn Sink);
+        let influx = self.influx.as_ref().map(|s| s as &dyn Sink);
+        [alerter, history, mqtt, influx].into_iter().flatten()
+    }
+
+    fn cell_type(&self, mac: &[u8; 6]) -> CellType {
+        self.cell_type_overrides
+            .get(mac)
+            .copied()
+            .unwrap_or(self.cell_type)
+    }
+}
+
+/// Ranges outside which readings are taken to be sensor faults.
+struct Bounds {
+    temperature: (f64, f64),
+    humidity: (f64, f64),
+    pressure: (f64, f64),
+    battery: (f64, f64),
+}
+
+impl Bounds {
+    const UNBOUNDED: Bounds = Bounds {
+        temperature: (f64::NEG_INFINITY, f64::INFINITY),
+        humidity: (f64::NEG_INFINITY, f64::INFINITY),
+        pressure: (f64::NEG_INFINITY, f64::INFINITY),
+        battery: (f64::NEG_INFINITY, f64::INFINITY),
+    };
+
+    /// A measurement's readings as sinks take them, leaving out those
+    /// outside the bounds without counting them.
+    fn readings(&self, m: &format::Measurement) -> [(&'static str, Option<f64>); 4] {
+        [
+            ("temperature", within(m.temperature, self.temperature)),
+            ("humidity", within(m.humidity, self.humidity)),
+            ("pressure", within(m.pressure, self.pressure)),
+            ("battery", within(m.battery, self.battery)),
+        ]
+    }
+
+    /// Puts the last plausible reading, if any, in place of each of `m`'s
+    /// readings outside the bounds, as the gauges hold theirs.
+    fn hold_plausible(&self, m: &mut format::Measurement, previous: &format::Measurement) {
+        let readings = [
+            (&mut m.temperature, previous.temperature, self.temperature),
+            (&mut m.humidity, previous.humidity, self.humidity),
+            (&mut m.pressure, previous.pressure, self.pressure),
+            (&mut m.battery, previous.battery, self.battery),
+        ];
+        for (value, previous, bounds) in readings {
+            if value.is_some() && within(*value, bounds).is_none() {
+                *value = previous;
+            }
+        }
+    }
+}
+
+/// `value` if it lies within `bounds`.
+fn within(value: Option<f64>, (min, max): (f64, f64)) -> Option<f64> {
+    value.filter(|v| (min..=max).contains(v))
+}
+
+/// Returns whether `value`, read from `msg`, lies within `bounds`,
+/// logging it as a rejection if not.
+fn plausible(
+    config: &Config,
+    clock: &impl Clock,
+    msg: &[u8],
+    metric: &str,
+    value: f64,
+    bounds: (f64, f64),
+    unit: &str,
+) -> bool {
+    if (bounds.0..=bounds.1).contains(&value) {
+        return true;
+    }
+    let message = format!("{} reported implausible {} {}", unit, metric, value);
+    reject(config, clock, "out_of_range", msg, Some(&message));
+    false
+}
+
+/// A scale temperature gauges are exported in. Bounds, comfort ranges,
+/// alerts and history stay in Celsius.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum TemperatureScale {
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureScale {
+    /// Converts a temperature in Celsius to this scale.
+    fn convert(self, celsius: f64) -> f64 {
+        match self {
+            TemperatureScale::Celsius => celsius,
+            TemperatureScale::Fahrenheit => celsius * 1.8 + 32.0,
+        }
+    }
+
+    /// The value of the scale label.
+    fn label(self) -> &'static str {
+        match self {
+            TemperatureScale::Celsius => "celsius",
+            TemperatureScale::Fahrenheit => "fahrenheit",
+        }
+    }
+}
+
+struct Comfort {
+    temperature: (f64, f64),
+    humidity: (f64, f64),
+}
+
+impl Comfort {
+    /// Temperature is judged before humidity when both are off.
+    fn category(&self, temperature: f64, humidity: f64) -> &'static str {
+        if temperature < self.temperature.0 {
+            "too_cold"
+        } else if temperature > self.temperature.1 {
+            "too_hot"
+        } else if humidity < self.humidity.0 {
+            "too_dry"
+        } else if humidity > self.humidity.1 {
+            "too_humid"
+        } else {
+            "comfortable"
+        }
+    }
+}
+
+/// Source of the current time for expiry and rate decisions, so that tests
+/// can step time instead of sleeping.
+trait Clock {
+    fn now(&self) -> Instant;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Somewhere accepted readings go besides the gauges. Readings are in
+/// Celsius, percent, kPa and Volts, with those missing or out of bounds as
+/// None. Implementations queue rather than block decoding.
+trait Sink {
+    fn record(&self, mac: &[u8; 6], name: &str, readings: &[(&'static str, Option<f64>)]);
+}
+
+struct SensorState {
+    /// The unit label of the sensor's series.
+    unit: String,
+    last_seen: Instant,
+    /// The wall time of the latest frame, which its series are stamped
+    /// with under --metric-timestamps.
+    seen_at: SystemTime,
+    ttl: Duration,
+    /// Sequence number and time at the start of the current rate window.
+    rate_anchor: Option<(u16, Instant)>,
+    /// Tracked but not exported, because it did not fit in --max-series.
+    /// Rechecked with each frame.
+    suppressed: bool,
+    /// Low-pass filtered pressure and when it was last updated.
+    filtered_pressure: Option<(f64, Instant)>,
+    /// Measurement sequence number of the latest frame.
+    last_sequence: Option<u16>,
+    /// Data format of the latest frame, as decoded.
+    data_format: u8,
+    /// Whether the optional series were exported for the latest frame.
+    optional: bool,
+    /// The latest frame's readings, for /sensors.json, with the last
+    /// plausible ones standing in for any out of bounds.
+    measurement: format::Measurement,
+}
+
+impl SensorState {
+    /// The wall time of the latest frame since the Unix epoch.
+    fn last_seen_unix(&self) -> Duration {
+        self.seen_at.duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+
+    /// Whether the sensor has not reported within its TTL.
+    fn stale(&self, now: Instant) -> bool {
+        self.last_seen + self.ttl < now
+    }
+
+    /// Whether the sensor has been stale for longer than `grace`, and its
+    /// series are to be removed.
+    fn expired(&self, now: Instant, grace: Duration) -> bool {
+        self.last_seen + self.ttl + grace < now
+    }
+
+    /// Feeds a pressure reading through a first-order low-pass filter
+    /// with time constant `tau`, returning the filtered value. The first
+    /// reading is taken as is.
+    fn filter_pressure(&mut self, pressure: f64, now: Instant, tau: Duration) -> f64 {
+        let filtered = match self.filtered_pressure {
+            Some((previous, at)) => {
+                let alpha = 1.0 - (-(now - at).as_secs_f64() / tau.as_secs_f64()).exp();
+                previous + alpha * (pressure - previous)
+            }
+            None => pressure,
+        };
+        self.filtered_pressure = Some((filtered, now));
+        filtered
+    }
+
+    /// Returns whether the measurement rate over the window that just
+    /// ended strays from the expected one, or None if the window is not
+    /// over yet.
+    fn check_rate(&mut self, seq: u16, now: Instant, config: &Config) -> Option<bool> {
+        let interval = config.measurement_interval?;
+        let (anchor_seq, anchor_time) = match self.rate_anchor {
+            Some(anchor) => anchor,
+            None => {
+                self.rate_anchor = Some((seq, now));
+                return None;
+            }
+        };
+        let elapsed = now - anchor_time;
+        if elapsed < RATE_WINDOW {
+            return None;
+        }
+        self.rate_anchor = Some((seq, now));
+        let expected = elapsed.as_secs_f64() / interval.as_secs_f64();
+        let ratio = seq.wrapping_sub(anchor_seq) as f64 / expected;
+        Some((ratio - 1.0).abs() > config.rate_tolerance)
+    }
+}
+
+fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split(':');
+    for b in mac.iter_mut() {
+        *b = parts
+            .next()
+            .and_then(|p| u8::from_str_radix(p, 16).ok())
+            .ok_or_else(|| format!("invalid MAC address: {}", s))?;
+    }
+    if parts.next().is_some() {
+        return Err(format!("invalid MAC address: {}", s));
+    }
+    Ok(mac)
+}
+
+fn parse_range(s: &str) -> Result<(f64, f64), String> {
+    let (min, max) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected MIN:MAX, got {}", s))?;
+    let min: f64 = min.parse().map_err(|e| format!("{}: {}", min, e))?;
+    let max: f64 = max.parse().map_err(|e| format!("{}: {}", max, e))?;
+    if min > max {
+        return Err(format!("{} is above {}", min, max));
+    }
+    Ok((min, max))
+}
+
+fn parse_cell_type(s: &str) -> Result<(Option<[u8; 6]>, CellType), String> {
+    let (mac, cell) = match s.split_once('=') {
+        Some((mac, cell)) => (Some(parse_mac(mac)?), cell),
+        None => (None, s),
+    };
+    Ok((mac, CellType::from_str(cell, true)?))
+}
+
+fn parse_label_name(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !s.starts_with("__");
+    if !valid {
+        return Err(format!("{} is not a valid Prometheus label name", s));
+    }
+    if OTHER_SENSOR_LABELS.contains(&s) {
+        return Err(format!("{} is already used as another label", s));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_usb_id(s: &str) -> Result<(u16, u16), String> {
+    let (vendor, product) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected VENDOR:PRODUCT, got {}", s))?;
+    let id = |id| u16::from_str_radix(id, 16).map_err(|e| format!("{}: {}", id, e));
+    Ok((id(vendor)?, id(product)?))
+}
+
+fn parse_sensor_ttl(s: &str) -> Result<(Option<[u8; 6]>, Duration), String> {
+    let (mac, secs) = match s.split_once('=') {
+        Some((mac, secs)) => (Some(parse_mac(mac)?), secs),
+        None => (None, s),
+    };
+    let secs: u64 = secs.parse().map_err(|e| format!("{}: {}", secs, e))?;
+    Ok((mac, Duration::from_secs(secs)))
+}
+
+fn load_names(path: &Path) -> Result<HashMap<[u8; 6], String>, Box<dyn std::error::Error>> {
+    parse_names(&fs::read_to_string(path)?)
+}
+
+/// Parses --names. Each name must be unique, or the sensors sharing it
+/// would share series and expire each other's.
+fn parse_names(text: &str) -> Result<HashMap<[u8; 6], String>, Box<dyn std::error::Error>> {
+    let names: HashMap<String, String> = toml::from_str(text)?;
+    let mut by_mac = HashMap::new();
+    let mut by_name = HashMap::new();
+    for (mac, name) in names {
+        let mac = parse_mac(&mac)?;
+        if let Some(other) = by_name.insert(name.clone(), mac) {
+            let (a, b) = (mac.min(other), mac.max(other));
+            return Err(format!(
+                "{} and {} are both named {}",
+                mac_string(&a),
+                mac_string(&b),
+                name
+            )
+            .into());
+        }
+        by_mac.insert(mac, name);
+    }
+    Ok(by_mac)
 }
 
 fn mac_string(mac: &[u8; 6]) -> String {
@@ -36,77 +1072,704 @@ fn mac_string(mac: &[u8; 6]) -> String {
     )
 }
 
-async fn got_message(msg: &[u8], sensors: &Mutex<HashMap<[u8; 6], Instant>>) {
-    if msg.len() < 4 {
-        eprintln!("too short");
-        return;
+/// Sets a gauge computed from other readings, or removes it if there is no
+/// value. Non-finite results of edge-case inputs are treated as no value
+/// so that they are never exported as NaN or Inf samples.
+fn set_derived(gauge: &GaugeVec, labels: &[&str], value: Option<f64>) {
+    match value.filter(|v| v.is_finite()) {
+        Some(v) => gauge.with_label_values(labels).set(v),
+        None => {
+            gauge.remove_label_values(labels).ok();
+        }
+    }
+}
+
+/// What got_message made of a frame.
+#[derive(Clone, Copy, PartialEq)]
+enum Frame {
+    /// Damaged or not authentic.
+    Invalid,
+    /// Intact, but not a format that is decoded.
+    Unsupported,
+    /// From a sensor left out by --allow-sensor or --deny-sensor. Like a
+    /// damaged frame, it says nothing about whether the link is useful.
+    Filtered,
+    Accepted,
+}
+
+/// Describes where a MAC address comes from. Only public addresses carry
+/// a vendor OUI; RuuviTags use random static ones.
+fn address_kind(mac: &[u8; 6]) -> &'static str {
+    if mac[0] & 0xc0 == 0xc0 {
+        "random static address"
+    } else {
+        "vendor unknown"
+    }
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Counts a frame rejected for `reason`. With --log-rejects, also logs it
+/// as a warning with the MAC it claims to be from, at most once per reason
+/// every REJECT_LOG_INTERVAL. Otherwise just logs `message`, if any, at
+/// debug level.
+fn reject(
+    config: &Config,
+    clock: &impl Clock,
+    reason: &'static str,
+    msg: &[u8],
+    message: Option<&str>,
+) {
+    REJECTED_FRAMES.with_label_values(&[reason]).inc();
+    let last_logged = match &config.reject_log {
+        Some(last_logged) => last_logged,
+        None => {
+            if let Some(message) = message {
+                debug!("{}", message);
+            }
+            return;
+        }
+    };
+    let now = clock.now();
+    let mut last_logged = last_logged.lock().unwrap();
+    if last_logged
+        .get(reason)
+        .is_some_and(|t| now < *t + REJECT_LOG_INTERVAL)
+    {
+        return;
+    }
+    last_logged.insert(reason, now);
+    let mac = match msg.get(format::mac_range(msg.get(6).copied())) {
+        Some(mac) => mac_string(mac.try_into().unwrap()),
+        None => "unknown MAC".to_string(),
+    };
+    warn!("Rejected frame from {}: {}", mac, reason);
+}
+
+/// Decodes a frame and updates the metrics from it.
+async fn got_message(
+    msg: &[u8],
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+    clock: &impl Clock,
+) -> Frame {
+    // Look for the traced MAC where the claimed format keeps it before
+    // trusting anything else about the frame, so rejections are traced too.
+    // Trace lines are at info level: --trace-mac already asks for them,
+    // and debug would bring every other tag's detail along.
+    let trace = traced(config, msg);
+    if trace {
+        info!("trace: raw {}", hex_string(msg));
+    }
+    if msg.len() < 4 {
+        reject(config, clock, "short", msg, Some("too short"));
+        return Frame::Invalid;
+    }
+    let sum_bytes: [u8; 4] = msg[..4].try_into().unwrap();
+    let got_sum = u32::from_be_bytes(sum_bytes);
+    let mut h = Hasher::new();
+    h.update(&msg[4..]);
+    let want_sum = h.finalize();
+    if got_sum != want_sum {
+        reject(config, clock, "crc", msg, Some("CRC32 mismatch"));
+        if trace {
+            info!("trace: rejected, CRC32 {:08x} != {:08x}", got_sum, want_sum);
+        }
+        return Frame::Invalid;
+    }
+    let msg = match &config.hmac_key {
+        Some(key) => {
+            if msg.len() < 4 + HMAC_LEN {
+                reject(config, clock, "hmac", msg, Some("too short for HMAC"));
+                HMAC_FAILURES.inc();
+                return Frame::Invalid;
+            }
+            let (data, tag) = msg.split_at(msg.len() - HMAC_LEN);
+            let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+            mac.update(&data[4..]);
+            // verify_slice compares in constant time.
+            if mac.verify_slice(tag).is_err() {
+                reject(config, clock, "hmac", msg, Some("HMAC mismatch"));
+                if trace {
+                    info!("trace: rejected, HMAC mismatch");
+                }
+                HMAC_FAILURES.inc();
+                return Frame::Invalid;
+            }
+            data
+        }
+        None => msg,
+    };
+    // The gateway may batch several records into one frame, each laid out
+    // after the last as it would be alone after the CRC. The parsers skip
+    // the CRC, so whatever precedes a record can stand in for it.
+    let mut offset = 0;
+    let mut result = None;
+    loop {
+        let record = &msg[offset..];
+        let trace = if offset == 0 {
+            trace
+        } else {
+            traced(config, record)
+        };
+        let frame = got_record(record, sensors, config, clock, trace).await;
+        result = match result {
+            Some(Frame::Accepted) => Some(Frame::Accepted),
+            Some(first) if frame != Frame::Accepted => Some(first),
+            _ => Some(frame),
+        };
+        match record.get(6).copied().and_then(format::record_len) {
+            Some(len) => offset += len,
+            None => break,
+        }
+        let rest = msg.get(offset + 4..).unwrap_or_default();
+        if rest.is_empty() {
+            break;
+        }
+        if !rest.starts_with(&[0x99, 0x04]) {
+            debug!("Ignoring {} bytes after the last record", rest.len());
+            break;
+        }
+    }
+    result.unwrap()
+}
+
+/// Whether the MAC where the claimed format keeps it is --trace-mac.
+fn traced(config: &Config, msg: &[u8]) -> bool {
+    match config.trace_mac {
+        Some(mac) => msg.get(format::mac_range(msg.get(6).copied())) == Some(&mac[..]),
+        None => false,
+    }
+}
+
+/// Decodes one record, preceded by four bytes in place of the CRC, and
+/// updates the metrics from it.
+async fn got_record(
+    msg: &[u8],
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+    clock: &impl Clock,
+    trace: bool,
+) -> Frame {
+    let ruuvi = msg.len() >= 7 && msg[4] == 0x99 && msg[5] == 0x04;
+    // Only a format the bridge has no parser of its own is taken as 5.
+    let assumed =
+        ruuvi && format::record_len(msg[6]).is_none() && config.assume_format5 && msg.len() == 30;
+    let parsed = match msg.get(6) {
+        Some(5) if ruuvi => Some(format::parse_format5(msg)),
+        _ if assumed => Some(format::parse_format5(msg)),
+        Some(3) if ruuvi => Some(format::parse_format3(msg)),
+        _ => None,
+    };
+    if let Some(parsed) = parsed {
+        let measurement = match parsed {
+            Ok(measurement) => measurement,
+            Err(e) => {
+                reject(config, clock, e.reason(), msg, Some(&e.to_string()));
+                if trace {
+                    info!("trace: rejected, {}", e);
+                }
+                return Frame::Unsupported;
+            }
+        };
+        let mac = measurement.mac;
+        if !config.admits(&mac) {
+            reject(config, clock, "filtered", msg, None);
+            if trace {
+                info!("trace: dropped by --allow-sensor or --deny-sensor");
+            }
+            return Frame::Filtered;
+        }
+        if assumed {
+            ASSUMED_FORMAT5.inc();
+        }
+
+        let mac_s = mac_string(&mac);
+        let unit = config.unit(&mac);
+        let labels = &[unit.as_str()];
+
+        let now = clock.now();
+        let seen_at = SystemTime::now();
+        let format = if assumed { 5 } else { msg[6] };
+        let optional = {
+            let mut sensors = sensors.lock().await;
+            let mut exported = sensors.values().filter(|s| !s.suppressed).count();
+            let state = sensors.entry(mac).or_insert_with(|| {
+                if config.new_logged.lock().unwrap().insert(mac) {
+                    info!(
+                        "New sensor {} ({}), data format {}",
+                        mac_s,
+                        address_kind(&mac),
+                        format
+                    );
+                }
+                let suppressed = config
+                    .max_series
+                    .is_some_and(|max| (exported + 1) * config.core_series() > max);
+                if suppressed {
+                    warn!(
+                        "Series budget exhausted, not exporting new sensor {}",
+                        mac_s
+                    );
+                } else {
+                    exported += 1;
+                }
+                SensorState {
+                    unit: unit.clone(),
+                    last_seen: now,
+                    seen_at,
+                    ttl: config.ttl(&mac),
+                    rate_anchor: None,
+                    suppressed,
+                    filtered_pressure: None,
+                    last_sequence: None,
+                    data_format: format,
+                    optional: false,
+                    // Nothing plausible yet.
+                    measurement: format::Measurement {
+                        temperature: None,
+                        humidity: None,
+                        pressure: None,
+                        battery: None,
+                        ..measurement.clone()
+                    },
+                }
+            });
+            // Budget freed by sensors that expired goes to those waiting.
+            let fits = config
+                .max_series
+                .is_none_or(|max| (exported + 1) * config.core_series() <= max);
+            if state.suppressed && fits {
+                info!("Series budget has room, now exporting sensor {}", mac_s);
+                state.suppressed = false;
+                exported += 1;
+            }
+            state.last_seen = now;
+            state.seen_at = seen_at;
+            // The gateway often relays the same advertisement several
+            // times over.
+            if let Some(seq) = measurement.sequence {
+                if state.last_sequence.replace(seq) == Some(seq) {
+                    reject(config, clock, "duplicate", msg, None);
+                    if trace {
+                        info!("trace: duplicate of sequence {}", seq);
+                    }
+                    return Frame::Accepted;
+                }
+            }
+            ACCEPTED_FRAMES.inc();
+            let previous = std::mem::replace(&mut state.measurement, measurement.clone());
+            config
+                .bounds
+                .hold_plausible(&mut state.measurement, &previous);
+            let suppressed = state.suppressed;
+            let optional = config.max_series.is_none_or(|max| {
+                exported * (config.core_series() + config.optional_series()) <= max
+            });
+            if !optional || sensors.values().any(|s| s.suppressed) {
+                if SERIES_BUDGET_EXCEEDED.get() == 0.0 {
+                    warn!("Series budget exceeded, withholding optional series");
+                }
+                SERIES_BUDGET_EXCEEDED.set(1.0);
+            } else {
+                SERIES_BUDGET_EXCEEDED.set(0.0);
+            }
+            if suppressed {
+                drop(sensors);
+                // The budget is on series, so the sinks still get the
+                // readings.
+                let readings = config.bounds.readings(&measurement);
+                for sink in config.sinks() {
+                    sink.record(&mac, &unit, &readings);
+                }
+                if trace {
+                    info!("trace: accepted but not exported, over --max-series");
+                }
+                return Frame::Accepted;
+            }
+            let state = sensors.get_mut(&mac).unwrap();
+            state.optional = optional;
+            if state.data_format != format {
+                let old = state.data_format.to_string();
+                SENSOR_INFO.remove_label_values(&[&unit, &old]).ok();
+                state.data_format = format;
+            }
+            let info_labels = &[unit.as_str(), &format.to_string()];
+            if optional {
+                SENSOR_INFO.with_label_values(info_labels).set(1.0);
+            } else {
+                SENSOR_INFO.remove_label_values(info_labels).ok();
+            }
+            if !optional {
+                RATE_ANOMALY.remove_label_values(labels).ok();
+            } else if let Some(seq) = measurement.sequence {
+                if let Some(anomaly) = state.check_rate(seq, now, config) {
+                    RATE_ANOMALY
+                        .with_label_values(labels)
+                        .set(if anomaly { 1.0 } else { 0.0 });
+                }
+            }
+            optional
+        };
+
+        let in_bounds = |metric, value, bounds| {
+            let ok = plausible(config, clock, msg, metric, value, bounds, &unit);
+            // The count is one of the optional series.
+            if !optional {
+                OUT_OF_RANGE.remove_label_values(&[metric, &unit]).ok();
+            } else if !ok {
+                OUT_OF_RANGE.with_label_values(&[metric, &unit]).inc();
+            }
+            ok
+        };
+        let temperature = match measurement.temperature {
+            None => {
+                remove_scales(&ROOM_TEMPERATURE, &unit);
+                None
+            }
+            // An implausible reading leaves the last good one in place.
+            Some(temperature) => {
+                let bounds = config.bounds.temperature;
+                in_bounds("temperature", temperature, bounds).then(|| {
+                    for scale in &config.temperature_scales {
+                        ROOM_TEMPERATURE
+                            .with_label_values(&[&unit, scale.label()])
+                            .set(scale.convert(temperature));
+                    }
+                    temperature
+                })
+            }
+        };
+        let humidity = match measurement.humidity {
+            None => {
+                HUMIDITY.remove_label_values(labels).ok();
+                None
+            }
+            Some(humidity) => {
+                let bounds = config.bounds.humidity;
+                in_bounds("humidity", humidity, bounds).then(|| {
+                    HUMIDITY.with_label_values(labels).set(humidity);
+                    humidity
+                })
+            }
+        };
+        // Like the readings themselves, what is derived from them is left
+        // in place when one of them is implausible.
+        let implausible = (measurement.temperature.is_some() && temperature.is_none())
+            || (measurement.humidity.is_some() && humidity.is_none());
+        if !implausible {
+            match (&config.comfort, temperature, humidity) {
+                (Some(comfort), Some(temperature), Some(humidity)) if optional => {
+                    let category = comfort.category(temperature, humidity);
+                    for c in COMFORT_CATEGORIES.iter().filter(|c| **c != category) {
+                        COMFORT.remove_label_values(&[&unit, c]).ok();
+                    }
+                    COMFORT.with_label_values(&[&unit, category]).set(1.0);
+                }
+                (Some(_), _, _) => remove_comfort(labels),
+                (None, _, _) => (),
+            }
+            let dew_point = match (temperature, humidity) {
+                (Some(temperature), Some(humidity)) if optional => {
+                    Some(humidity::dew_point(temperature, humidity))
+                }
+                _ => None,
+            };
+            for scale in &config.temperature_scales {
+                set_derived(
+                    &DEW_POINT,
+                    &[&unit, scale.label()],
+                    dew_point.map(|t| scale.convert(t)),
+                );
+            }
+        }
+        let pressure = if let Some(pressure) = measurement.pressure {
+            if in_bounds("pressure", pressure, config.bounds.pressure) {
+                PRESSURE.with_label_values(labels).set(pressure);
+                match config.pressure_filter {
+                    Some(tau) if optional => {
+                        let filtered = sensors
+                            .lock()
+                            .await
+                            .get_mut(&mac)
+                            .map(|state| state.filter_pressure(pressure, now, tau));
+                        set_derived(&PRESSURE_FILTERED, labels, filtered);
+                    }
+                    _ => {
+                        PRESSURE_FILTERED.remove_label_values(labels).ok();
+                    }
+                }
+                Some(pressure)
+            } else {
+                None
+            }
+        } else {
+            PRESSURE.remove_label_values(labels).ok();
+            PRESSURE_FILTERED.remove_label_values(labels).ok();
+            None
+        };
+        let battery = if let Some(battery) = measurement.battery {
+            if in_bounds("battery", battery, config.bounds.battery) {
+                BATTERY.with_label_values(labels).set(battery);
+                let percent = optional.then(|| battery::percent(config.cell_type(&mac), battery));
+                set_derived(&BATTERY_PERCENT, labels, percent);
+                Some(battery)
+            } else {
+                None
+            }
+        } else {
+            BATTERY.remove_label_values(labels).ok();
+            BATTERY_PERCENT.remove_label_values(labels).ok();
+            None
+        };
+        for (axis, value) in AXES.iter().zip(measurement.acceleration) {
+            let labels = &[unit.as_str(), axis];
+            match value {
+                Some(value) if optional => {
+                    ACCELERATION.with_label_values(labels).set(value);
+                }
+                _ => {
+                    ACCELERATION.remove_label_values(labels).ok();
+                }
+            }
+        }
+        match seen_at.duration_since(UNIX_EPOCH) {
+            Ok(t) if optional => LAST_SEEN.with_label_values(labels).set(t.as_secs_f64()),
+            _ => {
+                LAST_SEEN.remove_label_values(labels).ok();
+            }
+        }
+        let extras = [
+            (
+                &*ACCELERATION_MAGNITUDE,
+                measurement.acceleration_magnitude(),
+            ),
+            (&*TX_POWER, measurement.tx_power),
+            (&*MOVEMENT, measurement.movement.map(f64::from)),
+            (&*SEQUENCE, measurement.sequence.map(f64::from)),
+            (&*STALE, config.stale_grace.map(|_| 0.0)),
+        ];
+        for (gauge, value) in extras {
+            match value {
+                Some(value) if optional => gauge.with_label_values(labels).set(value),
+                _ => {
+                    gauge.remove_label_values(labels).ok();
+                }
+            }
+        }
+        let readings = [
+            ("temperature", temperature),
+            ("humidity", humidity),
+            ("pressure", pressure),
+            ("battery", battery),
+        ];
+        for sink in config.sinks() {
+            sink.record(&mac, &unit, &readings);
+        }
+        if trace {
+            info!(
+                "trace: accepted format {}{}: {:?}",
+                msg[6],
+                if assumed { " as 5" } else { "" },
+                measurement
+            );
+        }
+        return Frame::Accepted;
+    }
+    reject(config, clock, "unknown_format", msg, None);
+    if trace {
+        info!("trace: rejected, unsupported format");
+    }
+    Frame::Unsupported
+}
+
+/// The age of every tracked sensor's values, as of now. This is built
+/// afresh for each scrape rather than accumulated.
+async fn value_age(
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    clock: &impl Clock,
+) -> Histogram {
+    let age = Histogram::with_opts(
+        HistogramOpts::new(
+            "ruuvi_sensor_value_age_seconds",
+            "Time since each tracked sensor last reported, at scrape time",
+        )
+        .buckets(exponential_buckets(1.0, 2.0, 10).unwrap()),
+    )
+    .unwrap();
+    let now = clock.now();
+    for state in sensors.lock().await.values() {
+        age.observe(now.duration_since(state.last_seen).as_secs_f64());
+    }
+    age
+}
+
+/// The latest readings of every live sensor, as a JSON array.
+async fn sensors_json(
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+    clock: &impl Clock,
+) -> Response<Body> {
+    let now = clock.now();
+    let sensors = sensors.lock().await;
+    let mut live: Vec<_> = sensors
+        .iter()
+        .filter(|(_, state)| !state.stale(now))
+        .collect();
+    live.sort_by_key(|(mac, _)| **mac);
+    let array: Vec<_> = live
+        .into_iter()
+        .map(|(mac, state)| {
+            let m = &state.measurement;
+            let last_seen = state.last_seen_unix().as_secs_f64();
+            serde_json::json!({
+                "mac": mac_string(mac),
+                "name": config.names.get(mac),
+                "temperature": m.temperature.map(|t| config.temperature_scales[0].convert(t)),
+                "humidity": m.humidity,
+                "pressure": m.pressure,
+                "battery": m.battery,
+                "last_seen": last_seen,
+            })
+        })
+        .collect();
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::Value::from(array).to_string()))
+        .unwrap()
+}
+
+/// Stamps the gauges labelled with a sensor with the time of its latest
+/// frame. The info and stale series are left alone: they describe the
+/// sensor rather than a reading, and sensor_stale in particular is set
+/// long after the frame.
+async fn stamp_gauges(
+    families: &mut [MetricFamily],
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+) {
+    let last_seen: HashMap<_, _> = sensors
+        .lock()
+        .await
+        .values()
+        .map(|state| {
+            let ms = state.last_seen_unix().as_millis() as i64;
+            (state.unit.clone(), ms)
+        })
+        .collect();
+    let unstamped: Vec<_> = [&*SENSOR_INFO, &*STALE]
+        .iter()
+        .flat_map(|gauge| gauge.desc())
+        .map(|desc| desc.fq_name.clone())
+        .collect();
+    let gauges = families.iter_mut().filter(|family| {
+        family.get_field_type() == MetricType::GAUGE
+            && !unstamped.iter().any(|name| name == family.get_name())
+    });
+    for family in gauges {
+        for metric in family.mut_metric().iter_mut() {
+            let unit = metric
+                .get_label()
+                .iter()
+                .find(|label| label.get_name() == sensor_label());
+            if let Some(ms) = unit.and_then(|label| last_seen.get(label.get_value())) {
+                metric.set_timestamp_ms(*ms);
+            }
+        }
     }
-    let sum_bytes: [u8; 4] = msg[..4].try_into().unwrap();
-    let got_sum = u32::from_be_bytes(sum_bytes);
-    let mut h = Hasher::new();
-    h.update(&msg[4..]);
-    let want_sum = h.finalize();
-    if got_sum != want_sum {
-        eprintln!("CRC32 mismatch");
-        return;
+}
+
+/// Feeds the frames in a Ruuvi Gateway POST through got_message.
+async fn gateway_post(
+    req: Request<Body>,
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut body = req.into_body();
+    if body.size_hint().lower() > MAX_GATEWAY_BODY as u64 {
+        return Ok(text_response(413, "payload too large\n"));
     }
-    if msg.len() >= 30 && msg[4] == 0x99 && msg[5] == 0x04 && msg[6] == 5 {
-        // https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_05.md
-        let mac: [u8; 6] = msg[24..30].try_into().unwrap();
-        if mac == [0xff; 6] {
-            eprintln!("missing MAC");
-            return;
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk?);
+        if bytes.len() > MAX_GATEWAY_BODY {
+            return Ok(text_response(413, "payload too large\n"));
         }
-
-        let expiry = Instant::now() + Duration::from_secs(300);
-        sensors
-            .lock()
-            .await
-            .entry(mac)
-            .and_modify(|e| *e = expiry)
-            .or_insert(expiry);
-
-        let mac_s = mac_string(&mac);
-        let labels = &[mac_s.as_str()];
-        let temp_raw = i16::from_be_bytes(msg[7..9].try_into().unwrap());
-        if temp_raw == i16::MIN {
-            ROOM_TEMPERATURE.remove_label_values(labels).ok();
-        } else {
-            ROOM_TEMPERATURE
-                .with_label_values(labels)
-                .set(temp_raw as f64 * 0.005);
+    }
+    let (status, text) = match gateway::frames(&bytes) {
+        Ok(frames) => {
+            for msg in frames {
+                got_message(&msg, sensors, config, &SystemClock).await;
+            }
+            (200, String::new())
         }
-        let humidity_raw = u16::from_be_bytes(msg[9..11].try_into().unwrap());
-        if humidity_raw == u16::MAX {
-            HUMIDITY.remove_label_values(labels).ok();
-        } else {
-            HUMIDITY
-                .with_label_values(labels)
-                .set(humidity_raw as f64 * 0.0025);
+        Err(e) => {
+            warn!("Bad Ruuvi Gateway POST: {}", e);
+            (400, e)
         }
-        let pressure_raw = u16::from_be_bytes(msg[11..13].try_into().unwrap());
-        if pressure_raw == u16::MAX {
-            PRESSURE.remove_label_values(labels).ok();
-        } else {
-            PRESSURE
-                .with_label_values(labels)
-                .set(pressure_raw as f64 / 1000.0 + 50.0);
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::from(text))
+        .unwrap())
+}
+
+const LANDING_PAGE: &str = "<html><head><title>ruuvi_bridge</title></head>\
+<body><h1>ruuvi_bridge</h1><p><a href=\"/metrics\">Metrics</a></p>\
+<p><a href=\"/sensors.json\">Latest readings</a></p></body></html>\n";
+
+fn text_response(status: u16, text: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(text))
+        .unwrap()
+}
+
+async fn serve_req(
+    req: Request<Body>,
+    sensors: Arc<Mutex<HashMap<[u8; 6], SensorState>>>,
+    config: Arc<Config>,
+) -> Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path();
+    let post_ok = config.gateway_input && path == "/ruuvi";
+    if !post_ok && !matches!(path, "/" | "/metrics" | "/healthz" | "/sensors.json") {
+        return Ok(text_response(404, "not found\n"));
+    }
+    let allowed = if post_ok {
+        req.method() == Method::POST
+    } else {
+        req.method() == Method::GET || req.method() == Method::HEAD
+    };
+    if !allowed {
+        return Ok(text_response(405, "method not allowed\n"));
+    }
+    match path {
+        "/ruuvi" => return gateway_post(req, &sensors, &config).await,
+        "/healthz" => {
+            return Ok(match OPEN_DEVICES.load(Ordering::Relaxed) {
+                0 => text_response(503, "no serial device open\n"),
+                _ => text_response(200, "ok\n"),
+            })
         }
-        let power_raw = u16::from_be_bytes(msg[19..21].try_into().unwrap());
-        if power_raw >> 5 == 2047 {
-            BATTERY.remove_label_values(labels).ok();
-        } else {
-            BATTERY
-                .with_label_values(labels)
-                .set((power_raw >> 5) as f64 / 1000.0 + 1.6);
+        "/sensors.json" => return Ok(sensors_json(&sensors, &config, &SystemClock).await),
+        "/" => {
+            return Ok(Response::builder()
+                .status(200)
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(LANDING_PAGE))
+                .unwrap())
         }
+        _ => (),
     }
-}
-
-async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let encoder = TextEncoder::new();
 
-    let metric_families = prometheus::gather();
+    let mut metric_families = prometheus::gather();
+    metric_families.extend(value_age(&sensors, &SystemClock).await.collect());
+    if config.metric_timestamps {
+        stamp_gauges(&mut metric_families, &sensors).await;
+    }
     let mut buffer = vec![];
     encoder.encode(&metric_families, &mut buffer).unwrap();
 
@@ -119,191 +1782,1131 @@ async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error>
     Ok(response)
 }
 
-fn is_arduino(prefix: &PathBuf) -> bool {
-    match fs::read_to_string(prefix.join("device/../idVendor")) {
-        Ok(contents) if contents == "2341\n" => (),
-        _ => {
-            return false;
-        }
+/// Whether a tty's USB vendor and product IDs are among `usb_ids`.
+fn is_arduino(prefix: &Path, usb_ids: &[(u16, u16)]) -> bool {
+    let id = |name| {
+        let contents = fs::read_to_string(prefix.join("device/..").join(name)).ok()?;
+        u16::from_str_radix(contents.trim(), 16).ok()
     };
-    match fs::read_to_string(prefix.join("device/../idProduct")) {
-        Ok(contents) if contents == "8054\n" => true,
+    match (id("idVendor"), id("idProduct")) {
+        (Some(vendor), Some(product)) => usb_ids.contains(&(vendor, product)),
         _ => false,
     }
 }
 
-fn nibble(b: u8) -> Option<u8> {
-    match b {
-        0x30..=0x39 => Some(b - 0x30),
-        0x41..=0x4f => Some(b - 55),
-        0x61..=0x6f => Some(b - 87),
-        _ => None,
+/// Opens a serial device for reading without depending on modem control
+/// lines. A plain blocking open() of a tty waits for carrier detect unless
+/// CLOCAL is set, and adapters that never assert DCD (or that hold
+/// hardware flow control on connect) hang there forever. So open with
+/// O_NONBLOCK, set CLOCAL, clear HUPCL so that closing does not drop DTR
+/// (which resets many Arduino boards), then go back to blocking reads.
+fn open_tty(path: &Path) -> std::io::Result<File> {
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(path)?;
+    let fd = f.as_raw_fd();
+    // SAFETY: fd is a valid open descriptor owned by f for the duration.
+    unsafe {
+        let mut tio: libc::termios = std::mem::zeroed();
+        // Not being a tty at all (e.g. a FIFO) is fine; there is nothing
+        // to configure.
+        if libc::tcgetattr(fd, &mut tio) == 0 {
+            tio.c_cflag |= libc::CLOCAL;
+            tio.c_cflag &= !libc::HUPCL;
+            if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(File::from_std(f))
+}
+
+/// Serial devices currently open for reading, for /healthz.
+static OPEN_DEVICES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a device in OPEN_DEVICES for as long as it lives, including
+/// when its reader is aborted.
+struct OpenDevice;
+
+impl OpenDevice {
+    fn new() -> Self {
+        OPEN_DEVICES.fetch_add(1, Ordering::Relaxed);
+        OpenDevice
+    }
+}
+
+impl Drop for OpenDevice {
+    fn drop(&mut self) {
+        OPEN_DEVICES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Where a gateway's frames are read from.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Source {
+    Tty(PathBuf),
+    /// A serial port exposed as a raw TCP socket.
+    Tcp(String),
+}
+
+impl Source {
+    /// Whether the source may still produce data after an empty read.
+    /// A closed connection does not.
+    fn present(&self) -> bool {
+        match self {
+            Source::Tty(path) => path.exists(),
+            Source::Tcp(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Tty(path) => write!(f, "{}", path.display()),
+            Source::Tcp(addr) => write!(f, "{}", addr),
+        }
     }
 }
 
-enum ReadState {
-    Interstitial,
-    Open1,
-    Open2,
-    Nibble1,
-    Nibble2,
-    Close1,
-    Close2,
+async fn arduino_bridge(
+    source: &Source,
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+) -> std::io::Result<()> {
+    match source {
+        Source::Tty(path) => read_frames(open_tty(path)?, source, sensors, config).await,
+        Source::Tcp(addr) => {
+            let input = TcpStream::connect(addr).await?;
+            read_frames(input, source, sensors, config).await
+        }
+    }
 }
 
-async fn arduino_bridge(path: &Path, sensors: &Mutex<HashMap<[u8; 6], Instant>>) -> std::io::Result<()> {
-    let mut input = File::open(path).await?;
-    let mut msg = Vec::new();
-    let mut n = 0;
-    let mut state = ReadState::Interstitial;
+/// Feeds the frames read from `source`, opened as `input`, through
+/// got_message until it closes or the frame watchdog fires.
+async fn read_frames(
+    mut input: impl AsyncRead + Unpin,
+    source: &Source,
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    config: &Config,
+) -> std::io::Result<()> {
+    let _open = OpenDevice::new();
+    let mut framer = framing::Framer::new();
+    let mut probe_deadline = config
+        .probe_timeout
+        .map(|t| tokio::time::Instant::now() + t);
+    let device = source.to_string();
+    let mut window_end = tokio::time::Instant::now() + PARSE_RATIO_WINDOW;
+    let (mut frames, mut intact) = (0u32, 0u32);
+    let mut zero_reads = 0;
+    let mut last_frame = tokio::time::Instant::now();
     loop {
         let mut buffer = [0u8; 1024];
-        let count = input.read(&mut buffer).await?;
-        if count == 0 {
-            break;
+        let read = input.read(&mut buffer);
+        let watchdog_deadline = config.frame_watchdog.map(|t| last_frame + t);
+        let deadline = [probe_deadline, watchdog_deadline, Some(window_end)]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap();
+        let count = tokio::time::timeout_at(deadline, read)
+            .await
+            .ok()
+            .transpose()?;
+        // Partial frames the framer abandoned count as attempts too. A
+        // window with no attempts at all says nothing either way.
+        if tokio::time::Instant::now() >= window_end {
+            let attempts = frames + framer.aborts;
+            if attempts > 0 {
+                DEVICE_PARSE_SUCCESS_RATIO
+                    .with_label_values(&[&device])
+                    .set(intact as f64 / attempts as f64);
+            }
+            window_end = tokio::time::Instant::now() + PARSE_RATIO_WINDOW;
+            (frames, intact, framer.aborts) = (0, 0, 0);
         }
-        for b in buffer[..count].iter() {
-            match state {
-                ReadState::Interstitial => {
-                    if *b == 123 {
-                        state = ReadState::Open1;
-                    }
-                }
-                ReadState::Open1 => {
-                    state = if *b == 123 {
-                        ReadState::Open2
-                    } else {
-                        ReadState::Interstitial
-                    }
-                }
-                ReadState::Open2 => {
-                    state = if *b == 123 {
-                        msg = Vec::new();
-                        ReadState::Nibble1
-                    } else {
-                        ReadState::Interstitial
-                    }
-                }
-                ReadState::Nibble1 => {
-                    state = if let Some(nn) = nibble(*b) {
-                        n = nn;
-                        ReadState::Nibble2
-                    } else if *b == 125 {
-                        ReadState::Close1
-                    } else {
-                        ReadState::Interstitial
-                    }
-                }
-                ReadState::Nibble2 => {
-                    state = if let Some(nn) = nibble(*b) {
-                        msg.push(n << 4 | nn);
-                        if msg.len() < 500 {
-                            ReadState::Nibble1
-                        } else {
-                            ReadState::Interstitial // too long
-                        }
-                    } else {
-                        ReadState::Interstitial
-                    }
+        if let Some(deadline) = probe_deadline {
+            if tokio::time::Instant::now() >= deadline {
+                error!(
+                    "Probe failed: no valid frame from {} within {:?}; is the right firmware loaded?",
+                    source,
+                    config.probe_timeout.unwrap()
+                );
+                GATEWAY_PROTOCOL_OK.with_label_values(&[&device]).set(0.0);
+                probe_deadline = None;
+            }
+        }
+        if watchdog_deadline.is_some_and(|deadline| tokio::time::Instant::now() >= deadline) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "no valid frame in {:?}, reopening",
+                    config.frame_watchdog.unwrap()
+                ),
+            ));
+        }
+        let count = match count {
+            Some(0) => {
+                // Some adapters briefly report EOF while staying attached.
+                // Only give up on the session once the device node is gone
+                // or the empty reads persist.
+                if zero_reads < config.zero_read_retries && source.present() {
+                    zero_reads += 1;
+                    tokio::time::sleep(ZERO_READ_RETRY_DELAY).await;
+                    continue;
                 }
-                ReadState::Close1 => {
-                    state = if *b == 125 {
-                        ReadState::Close2
+                break;
+            }
+            Some(count) => count,
+            None => continue,
+        };
+        zero_reads = 0;
+        for b in buffer[..count].iter() {
+            let Some(mut msg) = framer.push(*b) else {
+                continue;
+            };
+            if config.no_crc {
+                msg = format::with_crc(&msg);
+            }
+            let frame = got_message(&msg, sensors, config, &SystemClock).await;
+            frames += 1;
+            if !matches!(frame, Frame::Invalid | Frame::Filtered) {
+                intact += 1;
+                last_frame = tokio::time::Instant::now();
+            }
+            if frame == Frame::Accepted && probe_deadline.is_some() {
+                info!("Probe succeeded: {} speaks the expected protocol", source);
+                GATEWAY_PROTOCOL_OK.with_label_values(&[&device]).set(1.0);
+                probe_deadline = None;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remove_comfort(labels: &[&str]) {
+    for category in COMFORT_CATEGORIES {
+        COMFORT.remove_label_values(&[labels[0], category]).ok();
+    }
+}
+
+/// Removes a temperature gauge's series for `unit` in every scale, not
+/// just those exported now.
+fn remove_scales(gauge: &GaugeVec, unit: &str) {
+    for scale in TemperatureScale::value_variants() {
+        gauge.remove_label_values(&[unit, scale.label()]).ok();
+    }
+}
+
+async fn expire_sensors(
+    sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+    grace: Option<Duration>,
+    clock: &impl Clock,
+) {
+    let now = clock.now();
+    if grace.is_some() {
+        for state in sensors.lock().await.values() {
+            if state.stale(now) && state.optional {
+                STALE.with_label_values(&[&state.unit]).set(1.0);
+            }
+        }
+    }
+    let grace = grace.unwrap_or_default();
+    let expired: Vec<_> = sensors
+        .lock()
+        .await
+        .values()
+        .filter(|state| state.expired(now, grace))
+        .map(|state| (state.unit.clone(), state.data_format))
+        .collect();
+    for (unit, data_format) in expired {
+        let labels = &[unit.as_str()];
+        SENSOR_INFO
+            .remove_label_values(&[&unit, &data_format.to_string()])
+            .ok();
+        remove_scales(&ROOM_TEMPERATURE, &unit);
+        remove_scales(&DEW_POINT, &unit);
+        HUMIDITY.remove_label_values(labels).ok();
+        PRESSURE.remove_label_values(labels).ok();
+        PRESSURE_FILTERED.remove_label_values(labels).ok();
+        BATTERY.remove_label_values(labels).ok();
+        BATTERY_PERCENT.remove_label_values(labels).ok();
+        RATE_ANOMALY.remove_label_values(labels).ok();
+        remove_comfort(labels);
+        for axis in AXES {
+            ACCELERATION.remove_label_values(&[&unit, axis]).ok();
+        }
+        ACCELERATION_MAGNITUDE.remove_label_values(labels).ok();
+        LAST_SEEN.remove_label_values(labels).ok();
+        TX_POWER.remove_label_values(labels).ok();
+        MOVEMENT.remove_label_values(labels).ok();
+        SEQUENCE.remove_label_values(labels).ok();
+        STALE.remove_label_values(labels).ok();
+        for metric in BOUNDED_METRICS {
+            OUT_OF_RANGE.remove_label_values(&[metric, &unit]).ok();
+        }
+    }
+    sensors
+        .lock()
+        .await
+        .retain(|_, state| !state.expired(now, grace));
+    if let Ok(t) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        SWEEPER_LAST_RUN.set(t.as_secs_f64());
+    }
+}
+
+/// The serial devices of the Arduinos attached.
+fn find_arduinos(usb_ids: &[(u16, u16)]) -> HashSet<PathBuf> {
+    let ttynames: Vec<_> = match fs::read_dir("/sys/class/tty") {
+        Ok(r) => r
+            .filter_map(|e| match e {
+                Ok(entry) => {
+                    if is_arduino(&entry.path(), usb_ids) {
+                        Some(entry.file_name())
                     } else {
-                        ReadState::Interstitial
-                    }
-                }
-                ReadState::Close2 => {
-                    if *b == 125 {
-                        got_message(&msg, sensors).await;
+                        None
                     }
-                    state = ReadState::Interstitial;
                 }
-            }
+                Err(_) => None,
+            })
+            .collect(),
+        Err(e) => {
+            error!("Scanning /sys/class/tty failed: {}", e);
+            Vec::new()
         }
+    };
+    ttynames
+        .into_iter()
+        .map(|ttyname| Path::new("/dev").join(ttyname))
+        .collect()
+}
+
+/// Removes the series labelled with a device that is gone.
+fn remove_device(source: &Source) {
+    let device = source.to_string();
+    DEVICE_PARSE_SUCCESS_RATIO
+        .remove_label_values(&[&device])
+        .ok();
+    GATEWAY_PROTOCOL_OK.remove_label_values(&[&device]).ok();
+}
+
+/// Compares the sensors being tracked with those expected, if any.
+async fn check_fleet(sensors: &Mutex<HashMap<[u8; 6], SensorState>>, config: &Config) {
+    if config.expected_sensors.is_empty() {
+        return;
     }
-    Ok(())
+    let sensors = sensors.lock().await;
+    let mut seen = config.expected_seen.lock().unwrap();
+    seen.extend(
+        sensors
+            .keys()
+            .filter(|mac| config.expected_sensors.contains(*mac)),
+    );
+    let missing = config.expected_sensors.len() - seen.len();
+    let unexpected = sensors
+        .keys()
+        .filter(|mac| !config.expected_sensors.contains(*mac))
+        .count();
+    EXPECTED_SENSORS_MISSING.set(missing as f64);
+    UNEXPECTED_SENSORS.set(unexpected as f64);
+}
+
+/// The first file descriptor systemd passes with socket activation.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Listening sockets passed in by systemd socket activation, if any.
+/// See sd_listen_fds(3).
+fn activated_listeners() -> Vec<std::net::TcpListener> {
+    let var = |name| std::env::var(name).ok()?.parse::<u32>().ok();
+    if var("LISTEN_PID") != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let count = var("LISTEN_FDS").unwrap_or(0) as i32;
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        // SAFETY: systemd hands these descriptors over to this process,
+        // and nothing else takes ownership of them.
+        .map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("installing SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+    info!("Shutting down");
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<_> = env::args_os().collect();
-    if args.len() != 2 {
-        eprintln!(
-            "Usage: {} export-listen",
-            args[0].to_string_lossy()
-        );
-        std::process::exit(3);
+    let args = Args::parse();
+    // Taken now, since whether there are any decides if an address to
+    // listen on is missing.
+    let activated = activated_listeners();
+    if args.export_listen.is_empty() && activated.is_empty() && args.command.is_none() {
+        Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "an EXPORT_LISTEN address is required unless systemd passes in listening sockets",
+            )
+            .exit();
+    }
+    SENSOR_LABEL.set(args.sensor_label.clone()).unwrap();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // The benchmark leaves the user's options, and sinks, out of it.
+    if let Some(Command::BenchDecode { frames }) = args.command {
+        bench::run(frames as usize).await;
+        return Ok(());
+    }
+    let hmac_key = match &args.hmac_key_file {
+        Some(path) => Some(fs::read(path)?.trim_ascii_end().to_vec()),
+        None => None,
+    };
+    let mut ttl = SENSOR_TTL;
+    let mut ttl_overrides = HashMap::new();
+    for (mac, secs) in args.sensor_ttl {
+        match mac {
+            Some(mac) => {
+                ttl_overrides.insert(mac, secs);
+            }
+            None => ttl = secs,
+        }
+    }
+    let mut temperature_scales = Vec::new();
+    for scale in args.temperature_scale {
+        if !temperature_scales.contains(&scale) {
+            temperature_scales.push(scale);
+        }
+    }
+    let mut cell_type = CellType::Cr2477;
+    let mut cell_type_overrides = HashMap::new();
+    for (mac, cell) in args.cell_type {
+        match mac {
+            Some(mac) => {
+                cell_type_overrides.insert(mac, cell);
+            }
+            None => cell_type = cell,
+        }
     }
-    let metric_addr: SocketAddr = args[1].to_string_lossy().into_owned().parse()?;
+    let config = Arc::new(Config {
+        ttl,
+        stale_grace: args.stale_grace.map(Duration::from_secs),
+        ttl_overrides,
+        probe_timeout: args.probe_timeout.map(Duration::from_secs),
+        assume_format5: args.assume_format5,
+        hmac_key,
+        measurement_interval: args.measurement_interval_ms.map(Duration::from_millis),
+        rate_tolerance: args.rate_tolerance,
+        trace_mac: args.trace_mac,
+        max_series: args.max_series,
+        zero_read_retries: args.zero_read_retries,
+        frame_watchdog: (args.frame_watchdog > 0)
+            .then_some(Duration::from_secs(args.frame_watchdog)),
+        comfort: args.comfort.then_some(Comfort {
+            temperature: args.comfort_temperature,
+            humidity: args.comfort_humidity,
+        }),
+        bounds: if args.no_bounds_check {
+            Bounds::UNBOUNDED
+        } else {
+            Bounds {
+                temperature: args.temperature_bounds,
+                humidity: args.humidity_bounds,
+                pressure: args.pressure_bounds,
+                battery: args.battery_bounds,
+            }
+        },
+        cell_type,
+        cell_type_overrides,
+        expected_sensors: args.expect_sensor.into_iter().collect(),
+        expected_seen: std::sync::Mutex::new(HashSet::new()),
+        new_logged: std::sync::Mutex::new(HashSet::new()),
+        allowed_sensors: args.allow_sensor.into_iter().collect(),
+        denied_sensors: args.deny_sensor.into_iter().collect(),
+        pressure_filter: args.pressure_filter_secs.map(Duration::from_secs),
+        alerter: args
+            .alert_webhook
+            .map(|url| alert::Alerter::spawn(url, args.alert)),
+        history: match &args.history_db {
+            Some(path) => Some(history::History::open(
+                path,
+                Duration::from_secs(args.history_retention_days * 86400),
+            )?),
+            None => None,
+        },
+        reject_log: args
+            .log_rejects
+            .then(|| std::sync::Mutex::new(HashMap::new())),
+        names: match &args.names {
+            Some(path) => load_names(path)?,
+            None => HashMap::new(),
+        },
+        gateway_input: args.gateway_input,
+        no_crc: args.no_crc,
+        temperature_scales,
+        metric_timestamps: args.metric_timestamps,
+        mqtt: args.mqtt_url.map(|broker| {
+            let client_id = args.mqtt_client_id.unwrap_or_else(mqtt::default_client_id);
+            mqtt::Publisher::spawn(broker, client_id, args.mqtt_topic_prefix)
+        }),
+        influx: match &args.influxdb_url {
+            Some(url) => {
+                let token = fs::read_to_string(args.influxdb_token_file.as_ref().unwrap())?;
+                Some(influx::Writer::spawn(
+                    url,
+                    args.influxdb_org.as_ref().unwrap(),
+                    args.influxdb_bucket.as_ref().unwrap(),
+                    token.trim(),
+                )?)
+            }
+            None => None,
+        },
+    });
 
-    let serve_future = Server::bind(&metric_addr).serve(make_service_fn(|_| async {
-        Ok::<_, hyper::Error>(service_fn(serve_req))
-    }));
+    let sensors = Arc::new(Mutex::new(HashMap::<[u8; 6], SensorState>::new()));
+
+    // Tells the servers to finish what they are doing and stop.
+    let (stop, stopped) = tokio::sync::watch::channel(());
+    let mut builders = Vec::new();
+    for listener in activated {
+        // Tokio needs the socket to be non-blocking, and systemd does not
+        // set it so unless told to.
+        let activated = listener
+            .set_nonblocking(true)
+            .and_then(|()| listener.local_addr());
+        match activated {
+            Ok(metric_addr) => match Server::from_tcp(listener) {
+                Ok(builder) => {
+                    info!("Listening on {} from systemd", metric_addr);
+                    builders.push((metric_addr, builder));
+                }
+                Err(e) => error!("Cannot use socket for {} from systemd: {}", metric_addr, e),
+            },
+            Err(e) => error!("Cannot use socket from systemd: {}", e),
+        }
+    }
+    if builders.is_empty() {
+        for metric_addr in args.export_listen {
+            match Server::try_bind(&metric_addr) {
+                Ok(builder) => builders.push((metric_addr, builder)),
+                Err(e) => error!("Cannot listen on {}: {}", metric_addr, e),
+            }
+        }
+    }
+    let mut servers = Vec::new();
+    for (metric_addr, builder) in builders {
+        let sensors_serve = sensors.clone();
+        let config_serve = config.clone();
+        let server = builder.serve(make_service_fn(move |_| {
+            let sensors = sensors_serve.clone();
+            let config = config_serve.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    serve_req(req, sensors.clone(), config.clone())
+                }))
+            }
+        }));
+        let mut stopped = stopped.clone();
+        let server = server.with_graceful_shutdown(async move {
+            stopped.changed().await.ok();
+        });
+        servers.push(tokio::spawn(async move {
+            if let Err(err) = server.await {
+                error!("server error on {}: {}", metric_addr, err);
+            }
+        }));
+    }
+    if servers.is_empty() {
+        return Err("could not listen on any address".into());
+    }
 
-    let sensors = Arc::new(Mutex::new(HashMap::<[u8; 6], Instant>::new()));
+    BUILD_INFO
+        .with_label_values(&[env!("CARGO_PKG_VERSION"), env!("GIT_COMMIT")])
+        .set(1.0);
+    // With --bluetooth, Arduinos are only read when named.
+    let serial = args.bluetooth.is_none() || !args.device.is_empty() || !args.tcp.is_empty();
+    // Without --device, Arduinos are only looked for when nothing else is
+    // named.
+    if !args.device.is_empty() || (args.bluetooth.is_none() && args.tcp.is_empty()) {
+        DATA_SOURCE.with_label_values(&["serial"]).set(1.0);
+    }
+    if !args.tcp.is_empty() {
+        DATA_SOURCE.with_label_values(&["tcp"]).set(1.0);
+    }
+    if config.gateway_input {
+        DATA_SOURCE.with_label_values(&["gateway"]).set(1.0);
+    }
+    let mut scanner = None;
+    if let Some(adapter) = args.bluetooth {
+        DATA_SOURCE.with_label_values(&["bluetooth"]).set(1.0);
+        warn!(
+            "Scanning on hci{} through a raw HCI socket is experimental, and competes with bluetoothd",
+            adapter
+        );
+        let sensors = sensors.clone();
+        let config = config.clone();
+        let mut stopped = stopped.clone();
+        scanner = Some(tokio::spawn(async move {
+            let mut retry_interval = SCAN_INTERVAL;
+            loop {
+                let started = Instant::now();
+                // Dropping the scan turns the adapter's scanning off.
+                let result = tokio::select! {
+                    result = bluetooth::scan(adapter, &sensors, &config) => result,
+                    _ = stopped.changed() => return,
+                };
+                match result {
+                    Ok(()) => warn!("Lost hci{}.", adapter),
+                    Err(e) => error!("Error scanning on hci{}: {}", adapter, e),
+                }
+                // Back off while the adapter keeps failing.
+                if started.elapsed() > retry_interval {
+                    retry_interval = SCAN_INTERVAL;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(retry_interval) => (),
+                    _ = stopped.changed() => return,
+                }
+                retry_interval = (retry_interval * 2).min(MAX_SCAN_INTERVAL);
+            }
+        }));
+    }
 
     let sensors_update = sensors.clone();
-    tokio::spawn(async move {
-        loop {
-            let maybe_ttyname = match fs::read_dir("/sys/class/tty") {
-                Ok(r) => r
-                    .filter_map(|e| {
-                        match e {
-                            Ok(entry) => {
-                                if is_arduino(&entry.path()) {
-                                    Some(entry.file_name())
-                                } else {
-                                    None
-                                }
-                            }
-                            Err(_) => None
+    let config_update = config.clone();
+    let devices = args.device;
+    let usb_ids = args.usb_id;
+    let tcp = args.tcp;
+    if serial {
+        tokio::spawn(async move {
+            // One reader per gateway, so that gateways come and go
+            // independently of each other.
+            let mut readers = HashMap::<Source, tokio::task::JoinHandle<()>>::new();
+            // Whether the previous scan found nothing, so that is logged once.
+            let mut found_none = None;
+            let mut scan_interval = SCAN_INTERVAL;
+            loop {
+                let mut sources: HashSet<_> = if devices.is_empty() && tcp.is_empty() {
+                    find_arduinos(&usb_ids)
+                } else {
+                    devices
+                        .iter()
+                        .filter(|path| path.exists())
+                        .cloned()
+                        .collect()
+                }
+                .into_iter()
+                .map(Source::Tty)
+                .collect();
+                sources.extend(tcp.iter().cloned().map(Source::Tcp));
+                // A replugged board can come back under another name, so drop
+                // the series of a device that is no longer there.
+                readers.retain(|source, reader| {
+                    if sources.contains(source) {
+                        return true;
+                    }
+                    reader.abort();
+                    warn!("Lost {}.", source);
+                    remove_device(source);
+                    false
+                });
+                let mut restarted = false;
+                for source in &sources {
+                    match readers.get(source) {
+                        Some(reader) if !reader.is_finished() => continue,
+                        Some(_) => restarted = true,
+                        None => info!("Using {}...", source),
+                    }
+                    let sensors = sensors_update.clone();
+                    let config = config_update.clone();
+                    let reader_source = source.clone();
+                    let reader = tokio::spawn(async move {
+                        if let Err(e) = arduino_bridge(&reader_source, &sensors, &config).await {
+                            error!("Error reading from {}: {}", reader_source, e);
                         }
-                    })
-                    .nth(0),
-                Err(e) => {
-                    eprintln!("Scanning /sys/class/tty failed: {}", e);
-                    None
+                    });
+                    readers.insert(source.clone(), reader);
                 }
-            };
-            if let Some(ttyname) = maybe_ttyname {
-                let path = Path::new("/dev").join(ttyname);
-                println!("Using {}...", path.display());
-                if let Err(e) = arduino_bridge(&path, &sensors_update).await {
-                    eprintln!("Error reading from Arduino: {}", e);
+                if sources.is_empty() && found_none != Some(true) {
+                    warn!("Found no device to read from.");
+                }
+                found_none = Some(sources.is_empty());
+                // Back off while there is nothing to read, or readers keep
+                // failing, until every reader survives a whole interval.
+                let back_off = sources.is_empty() || restarted;
+                if !back_off {
+                    scan_interval = SCAN_INTERVAL;
+                }
+                tokio::time::sleep(scan_interval).await;
+                if back_off {
+                    scan_interval = (scan_interval * 2).min(MAX_SCAN_INTERVAL);
                 }
-            } else {
-                eprintln!("Found no device to read from.");
             }
-            tokio::time::sleep(Duration::from_secs(10)).await;
-        }
-    });
+        });
+    }
+    let config_shutdown = config.clone();
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(10));
         loop {
-            interval.tick().await;
-            let now = Instant::now();
-            let expired: Vec<_> = sensors
-                .lock()
-                .await
-                .iter()
-                .filter(|(_, expiry)| **expiry < now)
-                .map(|(k, _)| *k)
-                .collect();
-            for mac in expired {
-                let mac_s = mac_string(&mac);
-                let labels = &[mac_s.as_str()];
-                ROOM_TEMPERATURE.remove_label_values(labels).ok();
-                HUMIDITY.remove_label_values(labels).ok();
-                PRESSURE.remove_label_values(labels).ok();
-                BATTERY.remove_label_values(labels).ok();
+            let sensors = sensors.clone();
+            let config = config.clone();
+            let sweeper = tokio::spawn(async move {
+                let period = (config.ttl / SWEEPS_PER_TTL)
+                    .clamp(Duration::from_secs(1), Duration::from_secs(10));
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    expire_sensors(&sensors, config.stale_grace, &SystemClock).await;
+                    check_fleet(&sensors, &config).await;
+                }
+            });
+            if let Err(e) = sweeper.await {
+                error!("Sweeper task died, restarting: {}", e);
+                tokio::time::sleep(Duration::from_secs(10)).await;
             }
-            sensors.lock().await.retain(|_, &mut expiry| expiry >= now);
         }
     });
 
-    if let Err(err) = serve_future.await {
-        eprintln!("server error: {}", err);
+    shutdown_signal().await;
+    stop.send(()).ok();
+    for server in servers {
+        server.await.ok();
+    }
+    if let Some(scanner) = scanner {
+        scanner.await.ok();
+    }
+    // Of the other tasks, only history and InfluxDB have anything left to
+    // write.
+    if let Some(history) = &config_shutdown.history {
+        history.flush();
+    }
+    if let Some(influx) = &config_shutdown.influx {
+        if tokio::time::timeout(INFLUX_SHUTDOWN_TIMEOUT, influx.flush())
+            .await
+            .is_err()
+        {
+            warn!("Gave up on writing the last readings to InfluxDB");
+        }
+    }
+    // Exit without waiting for the runtime to shut down, which would wait
+    // for a reader blocked on a quiet gateway to read something.
+    std::process::exit(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock that only moves when told to.
+    struct MockClock(std::cell::Cell<Instant>);
+
+    impl MockClock {
+        fn new() -> Self {
+            MockClock(std::cell::Cell::new(Instant::now()))
+        }
+
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    /// A format 5 frame from the tag c0:ff:ee:0:0:`id`. Tests use distinct
+    /// tags since the metrics they check are global.
+    fn frame(id: u8, seq: u16) -> Vec<u8> {
+        let mut data = vec![0x99, 0x04, 0x05, 0x12, 0xfc, 0x53, 0x94, 0xc3, 0x7c];
+        data.extend_from_slice(&[0x00, 0x04, 0xff, 0xfc, 0x04, 0x0c, 0xac, 0x36, 0x42]);
+        data.extend_from_slice(&seq.to_be_bytes());
+        data.extend_from_slice(&[0xc0, 0xff, 0xee, 0, 0, id]);
+        format::with_crc(&data)
+    }
+
+    /// A frame batching `records`, each given as the bytes that follow the
+    /// CRC.
+    fn batch(records: &[&[u8]]) -> Vec<u8> {
+        format::with_crc(&records.concat())
+    }
+
+    #[tokio::test]
+    async fn batched_records_all_decode() {
+        let sensors = Mutex::new(HashMap::new());
+        let msg = batch(&[&frame(18, 1)[4..], &frame(19, 1)[4..]]);
+        let frame = got_message(&msg, &sensors, &Config::default(), &SystemClock).await;
+        assert!(frame == Frame::Accepted);
+        let sensors = sensors.lock().await;
+        assert!(sensors.contains_key(&[0xc0, 0xff, 0xee, 0, 0, 18]));
+        assert!(sensors.contains_key(&[0xc0, 0xff, 0xee, 0, 0, 19]));
+    }
+
+    #[tokio::test]
+    async fn short_batched_record_is_rejected_alone() {
+        let sensors = Mutex::new(HashMap::new());
+        let msg = batch(&[&frame(20, 1)[4..], &frame(21, 1)[4..20]]);
+        let frame = got_message(&msg, &sensors, &Config::default(), &SystemClock).await;
+        assert!(frame == Frame::Accepted);
+        let sensors = sensors.lock().await;
+        assert!(sensors.contains_key(&[0xc0, 0xff, 0xee, 0, 0, 20]));
+        assert_eq!(sensors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn trailing_bytes_after_batch_are_ignored() {
+        let sensors = Mutex::new(HashMap::new());
+        let msg = batch(&[&frame(22, 1)[4..], &[0xde, 0xad, 0xbe, 0xef, 0x00]]);
+        let frame = got_message(&msg, &sensors, &Config::default(), &SystemClock).await;
+        assert!(frame == Frame::Accepted);
+        let sensors = sensors.lock().await;
+        assert!(sensors.contains_key(&[0xc0, 0xff, 0xee, 0, 0, 22]));
+        assert_eq!(sensors.len(), 1);
+    }
+
+    /// The state of the tag with `id` after one frame from it.
+    async fn sensor_state(id: u8) -> SensorState {
+        let sensors = Mutex::new(HashMap::new());
+        got_message(&frame(id, 1), &sensors, &Config::default(), &SystemClock).await;
+        let mac = [0xc0, 0xff, 0xee, 0, 0, id];
+        let state = sensors.lock().await.remove(&mac).unwrap();
+        state
+    }
+
+    /// Whether `unit` has a series of the metric `name`.
+    fn exported(name: &str, unit: &str) -> bool {
+        prometheus::gather()
+            .iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| family.get_metric())
+            .any(|metric| metric.get_label().iter().any(|l| l.get_value() == unit))
+    }
+
+    /// The timestamp of each of `unit`'s series after stamping, by metric
+    /// name.
+    async fn stamps(
+        sensors: &Mutex<HashMap<[u8; 6], SensorState>>,
+        unit: &str,
+    ) -> HashMap<String, i64> {
+        let mut families = prometheus::gather();
+        stamp_gauges(&mut families, sensors).await;
+        let mut stamps = HashMap::new();
+        for family in &families {
+            for metric in family.get_metric() {
+                if metric.get_label().iter().any(|l| l.get_value() == unit) {
+                    stamps.insert(family.get_name().to_string(), metric.get_timestamp_ms());
+                }
+            }
+        }
+        stamps
+    }
+
+    #[tokio::test]
+    async fn metric_timestamps_are_stable() {
+        let sensors = Mutex::new(HashMap::new());
+        let config = Config {
+            stale_grace: Some(Duration::from_secs(60)),
+            ..Config::default()
+        };
+        got_message(&frame(1, 1), &sensors, &config, &SystemClock).await;
+        let seen_at = sensors.lock().await[&[0xc0, 0xff, 0xee, 0, 0, 1]]
+            .last_seen_unix()
+            .as_millis() as i64;
+        let first = stamps(&sensors, "c0:ff:ee:0:0:1").await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = stamps(&sensors, "c0:ff:ee:0:0:1").await;
+        assert_eq!(first, second);
+        assert_eq!(first["room_temperature"], seen_at);
+        assert_eq!(first["sensor_stale"], 0);
+        assert_eq!(first["ruuvi_sensor_info"], 0);
+    }
+
+    #[tokio::test]
+    async fn sensors_expire_after_ttl() {
+        let sensors = Mutex::new(HashMap::new());
+        let clock = MockClock::new();
+        got_message(&frame(2, 1), &sensors, &Config::default(), &clock).await;
+        clock.advance(SENSOR_TTL);
+        expire_sensors(&sensors, None, &clock).await;
+        assert_eq!(sensors.lock().await.len(), 1);
+        assert!(exported("room_temperature", "c0:ff:ee:0:0:2"));
+        clock.advance(Duration::from_secs(1));
+        expire_sensors(&sensors, None, &clock).await;
+        assert!(sensors.lock().await.is_empty());
+        assert!(!exported("room_temperature", "c0:ff:ee:0:0:2"));
+    }
+
+    #[tokio::test]
+    async fn sensor_ttl_overrides_apply_per_mac() {
+        let sensors = Mutex::new(HashMap::new());
+        let clock = MockClock::new();
+        let long_lived = [0xc0, 0xff, 0xee, 0, 0, 4];
+        let config = Config {
+            ttl_overrides: HashMap::from([(long_lived, Duration::from_secs(900))]),
+            ..Config::default()
+        };
+        got_message(&frame(3, 1), &sensors, &config, &clock).await;
+        got_message(&frame(4, 1), &sensors, &config, &clock).await;
+        clock.advance(Duration::from_secs(600));
+        expire_sensors(&sensors, None, &clock).await;
+        let macs: Vec<_> = sensors.lock().await.keys().copied().collect();
+        assert_eq!(macs, [long_lived]);
+        clock.advance(Duration::from_secs(301));
+        expire_sensors(&sensors, None, &clock).await;
+        assert!(sensors.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_grace_holds_series() {
+        let sensors = Mutex::new(HashMap::new());
+        let clock = MockClock::new();
+        let grace = Some(Duration::from_secs(60));
+        let config = Config {
+            stale_grace: grace,
+            ..Config::default()
+        };
+        got_message(&frame(5, 1), &sensors, &config, &clock).await;
+        assert_eq!(STALE.with_label_values(&["c0:ff:ee:0:0:5"]).get(), 0.0);
+        clock.advance(SENSOR_TTL + Duration::from_secs(1));
+        expire_sensors(&sensors, grace, &clock).await;
+        assert_eq!(sensors.lock().await.len(), 1);
+        assert_eq!(STALE.with_label_values(&["c0:ff:ee:0:0:5"]).get(), 1.0);
+        assert!(exported("room_temperature", "c0:ff:ee:0:0:5"));
+        clock.advance(Duration::from_secs(60));
+        expire_sensors(&sensors, grace, &clock).await;
+        assert!(sensors.lock().await.is_empty());
+        assert!(!exported("sensor_stale", "c0:ff:ee:0:0:5"));
+        assert!(!exported("room_temperature", "c0:ff:ee:0:0:5"));
+    }
+
+    #[tokio::test]
+    async fn suppressed_sensor_exported_once_budget_frees() {
+        let sensors = Mutex::new(HashMap::new());
+        let clock = MockClock::new();
+        let config = Config {
+            max_series: Some(CORE_SERIES),
+            ..Config::default()
+        };
+        let waiting = [0xc0, 0xff, 0xee, 0, 0, 7];
+        got_message(&frame(6, 1), &sensors, &config, &clock).await;
+        got_message(&frame(7, 1), &sensors, &config, &clock).await;
+        assert!(sensors.lock().await[&waiting].suppressed);
+        assert!(!exported("room_temperature", "c0:ff:ee:0:0:7"));
+        clock.advance(Duration::from_secs(200));
+        got_message(&frame(7, 2), &sensors, &config, &clock).await;
+        assert!(sensors.lock().await[&waiting].suppressed);
+        clock.advance(Duration::from_secs(101));
+        expire_sensors(&sensors, None, &clock).await;
+        got_message(&frame(7, 3), &sensors, &config, &clock).await;
+        assert!(!sensors.lock().await[&waiting].suppressed);
+        assert!(exported("room_temperature", "c0:ff:ee:0:0:7"));
+    }
+
+    /// Reads each of its chunks in turn, an empty one reading as end of
+    /// file, and then end of file for good.
+    struct Chunks(std::collections::VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context,
+            buf: &mut tokio::io::ReadBuf,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buf.put_slice(&chunk);
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The last sequence number read from the tag with `id` when its two
+    /// frames are separated by an empty read.
+    async fn read_past_empty_read(id: u8, config: &Config) -> Option<u16> {
+        let framed = |seq| {
+            ["{{{", &hex_string(&frame(id, seq)), "}}}"]
+                .concat()
+                .into_bytes()
+        };
+        let input = Chunks([framed(1), vec![], framed(2)].into());
+        let sensors = Mutex::new(HashMap::new());
+        // The root directory is always present.
+        let source = Source::Tty(PathBuf::from("/"));
+        read_frames(input, &source, &sensors, config).await.unwrap();
+        let mac = [0xc0, 0xff, 0xee, 0, 0, id];
+        let last_sequence = sensors.lock().await[&mac].last_sequence;
+        last_sequence
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_read_retries_read_on() {
+        assert_eq!(read_past_empty_read(9, &Config::default()).await, Some(1));
+        let config = Config {
+            zero_read_retries: 1,
+            ..Config::default()
+        };
+        assert_eq!(read_past_empty_read(10, &config).await, Some(2));
+    }
+
+    #[tokio::test]
+    async fn pressure_filter_time_constant() {
+        let mut state = sensor_state(11).await;
+        let tau = Duration::from_secs(600);
+        let start = Instant::now();
+        assert_eq!(state.filter_pressure(100.0, start, tau), 100.0);
+        // A step is 1 - 1/e of the way there after one time constant,
+        // however it is sampled.
+        let mut filtered = 0.0;
+        for i in 1..=10 {
+            filtered = state.filter_pressure(101.0, start + tau * i / 10, tau);
+        }
+        assert!((filtered - (101.0 - (-1.0f64).exp())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn set_derived_removes_non_finite_values() {
+        let labels = &["set-derived"];
+        let scaled = &["set-derived", "celsius"];
+        set_derived(&DEW_POINT, scaled, Some(humidity::dew_point(20.0, 50.0)));
+        assert!(exported("dew_point", "set-derived"));
+        // No dew point at 0% humidity: the logarithm goes to -inf.
+        set_derived(&DEW_POINT, scaled, Some(humidity::dew_point(20.0, 0.0)));
+        assert!(!exported("dew_point", "set-derived"));
+        set_derived(&PRESSURE_FILTERED, labels, Some(100.0));
+        assert!(exported("air_pressure_filtered", "set-derived"));
+        set_derived(&PRESSURE_FILTERED, labels, Some(f64::NAN));
+        assert!(!exported("air_pressure_filtered", "set-derived"));
+        set_derived(&PRESSURE_FILTERED, labels, None);
+        assert!(!exported("air_pressure_filtered", "set-derived"));
+    }
+
+    #[tokio::test]
+    async fn sensors_json_holds_plausible_readings() {
+        let sensors = Mutex::new(HashMap::new());
+        let mac = [0xc0, 0xff, 0xee, 0, 0, 12];
+        // The frames' 24.3 degrees is out of these bounds.
+        let strict = Config {
+            bounds: Bounds {
+                temperature: (-80.0, 20.0),
+                ..Bounds::UNBOUNDED
+            },
+            ..Config::default()
+        };
+        got_message(&frame(12, 1), &sensors, &strict, &SystemClock).await;
+        assert_eq!(sensors.lock().await[&mac].measurement.temperature, None);
+        got_message(&frame(12, 2), &sensors, &Config::default(), &SystemClock).await;
+        got_message(&frame(12, 3), &sensors, &strict, &SystemClock).await;
+        let m = sensors.lock().await[&mac].measurement.clone();
+        assert_eq!(m.temperature, Some(24.3));
+        assert_eq!(m.sequence, Some(3));
+    }
+
+    #[tokio::test]
+    async fn expected_sensors_missing_until_first_seen() {
+        let sensors = Mutex::new(HashMap::new());
+        let clock = MockClock::new();
+        let config = Config {
+            expected_sensors: HashSet::from([
+                [0xc0, 0xff, 0xee, 0, 0, 13],
+                [0xc0, 0xff, 0xee, 0, 0, 14],
+            ]),
+            ..Config::default()
+        };
+        check_fleet(&sensors, &config).await;
+        assert_eq!(EXPECTED_SENSORS_MISSING.get(), 2.0);
+        got_message(&frame(13, 1), &sensors, &config, &clock).await;
+        got_message(&frame(15, 1), &sensors, &config, &clock).await;
+        check_fleet(&sensors, &config).await;
+        assert_eq!(EXPECTED_SENSORS_MISSING.get(), 1.0);
+        assert_eq!(UNEXPECTED_SENSORS.get(), 1.0);
+        clock.advance(SENSOR_TTL * 2);
+        expire_sensors(&sensors, None, &clock).await;
+        check_fleet(&sensors, &config).await;
+        assert_eq!(EXPECTED_SENSORS_MISSING.get(), 1.0);
+        assert_eq!(UNEXPECTED_SENSORS.get(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn denied_sensor_frames_are_filtered() {
+        let sensors = Mutex::new(HashMap::new());
+        let config = Config {
+            denied_sensors: HashSet::from([[0xc0, 0xff, 0xee, 0, 0, 16]]),
+            ..Config::default()
+        };
+        let frame = got_message(&frame(16, 1), &sensors, &config, &SystemClock).await;
+        assert!(frame == Frame::Filtered);
+        assert!(sensors.lock().await.is_empty());
+        assert!(!exported("room_temperature", "c0:ff:ee:0:0:10"));
+    }
+
+    #[tokio::test]
+    async fn implausible_reading_holds_derived_values() {
+        let sensors = Mutex::new(HashMap::new());
+        let unit = "c0:ff:ee:0:0:11";
+        let config = Config {
+            comfort: Some(Comfort {
+                temperature: (20.0, 25.0),
+                humidity: (30.0, 60.0),
+            }),
+            ..Config::default()
+        };
+        got_message(&frame(17, 1), &sensors, &config, &SystemClock).await;
+        assert!(exported("dew_point", unit));
+        assert!(exported("ruuvi_comfort", unit));
+        // The frames' 24.3 degrees is out of these bounds.
+        let strict = Config {
+            bounds: Bounds {
+                temperature: (-80.0, 20.0),
+                ..Bounds::UNBOUNDED
+            },
+            ..config
+        };
+        got_message(&frame(17, 2), &sensors, &strict, &SystemClock).await;
+        assert!(exported("dew_point", unit));
+        assert!(exported("ruuvi_comfort", unit));
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let names = parse_names("\"c0:ff:ee:0:0:1\" = \"attic\"\n").unwrap();
+        assert_eq!(names[&[0xc0, 0xff, 0xee, 0, 0, 1]], "attic");
+        let err = parse_names("\"c0:ff:ee:0:0:1\" = \"attic\"\n\"c0:ff:ee:0:0:2\" = \"attic\"\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "c0:ff:ee:0:0:1 and c0:ff:ee:0:0:2 are both named attic"
+        );
+    }
+
+    #[tokio::test]
+    async fn temperatures_exported_in_each_scale() {
+        let sensors = Mutex::new(HashMap::new());
+        let clock = MockClock::new();
+        let unit = "c0:ff:ee:0:0:17";
+        let config = Config {
+            temperature_scales: vec![TemperatureScale::Celsius, TemperatureScale::Fahrenheit],
+            ..Config::default()
+        };
+        got_message(&frame(23, 1), &sensors, &config, &clock).await;
+        let celsius = ROOM_TEMPERATURE.with_label_values(&[unit, "celsius"]).get();
+        let fahrenheit = ROOM_TEMPERATURE
+            .with_label_values(&[unit, "fahrenheit"])
+            .get();
+        assert_eq!(celsius, 24.3);
+        assert!((fahrenheit - 75.74).abs() < 1e-9);
+        let celsius = DEW_POINT.with_label_values(&[unit, "celsius"]).get();
+        let fahrenheit = DEW_POINT.with_label_values(&[unit, "fahrenheit"]).get();
+        assert!((fahrenheit - (celsius * 1.8 + 32.0)).abs() < 1e-9);
+        clock.advance(SENSOR_TTL * 2);
+        expire_sensors(&sensors, None, &clock).await;
+        assert!(!exported("room_temperature", unit));
+        assert!(!exported("dew_point", unit));
     }
-    Ok(())
 }
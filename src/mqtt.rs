@@ -0,0 +1,115 @@
+//! Publishing of every measurement to an MQTT broker, e.g. for Home
+//! Assistant.
+
+use crate::Sink;
+use log::{info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::{json, Map, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Publications waiting to be sent. Beyond this, new ones are dropped
+/// rather than holding up decoding.
+const QUEUE_LENGTH: usize = 64;
+
+/// How long to wait before reconnecting after the connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Parses mqtt://HOST[:PORT] into a host and port.
+pub fn parse_url(s: &str) -> Result<(String, u16), String> {
+    let uri: hyper::Uri = s.parse().map_err(|e| format!("{}: {}", s, e))?;
+    if uri.scheme_str() != Some("mqtt") {
+        return Err(format!("expected mqtt://HOST[:PORT], got {}", s));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| format!("no host in {}", s))?
+        .to_string();
+    Ok((host, uri.port_u16().unwrap_or(1883)))
+}
+
+/// A client ID for this host, since a broker drops a client when another
+/// connects with the same ID. Falls back on the PID if the hostname is
+/// not available.
+pub fn default_client_id() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its length.
+    let ret = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    let len = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    match std::str::from_utf8(&buffer[..len]) {
+        Ok(host) if ret == 0 && !host.is_empty() => format!("ruuvi_bridge-{}", host),
+        _ => format!("ruuvi_bridge-{}", std::process::id()),
+    }
+}
+
+pub struct Publisher {
+    client: AsyncClient,
+    prefix: String,
+    /// Whether publications are being dropped, so that is logged once.
+    dropping: AtomicBool,
+}
+
+impl Publisher {
+    /// Starts a task that keeps a connection to the broker, reconnecting
+    /// whenever it drops. Measurements go to PREFIX/MAC.
+    pub fn spawn((host, port): (String, u16), client_id: String, prefix: String) -> Self {
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut events) = AsyncClient::new(options, QUEUE_LENGTH);
+        tokio::spawn(async move {
+            let mut connected = true;
+            loop {
+                match events.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        connected = true;
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        if connected {
+                            warn!("MQTT connection failed, retrying: {}", e);
+                            connected = false;
+                        }
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+        Publisher {
+            client,
+            prefix,
+            dropping: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Sink for Publisher {
+    fn record(&self, mac: &[u8; 6], name: &str, readings: &[(&'static str, Option<f64>)]) {
+        let mac_s = crate::mac_string(mac);
+        let mut payload = Map::new();
+        payload.insert("mac".to_string(), json!(mac_s));
+        payload.insert("name".to_string(), json!(name));
+        for (metric, value) in readings {
+            if let Some(value) = value {
+                payload.insert(metric.to_string(), json!(value));
+            }
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        payload.insert("timestamp".to_string(), json!(timestamp));
+        let topic = format!("{}/{}", self.prefix, mac_s);
+        let payload = Value::Object(payload).to_string();
+        match self
+            .client
+            .try_publish(topic, QoS::AtMostOnce, false, payload)
+        {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(_) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("MQTT queue full, dropping readings until it drains");
+                }
+            }
+        }
+    }
+}